@@ -0,0 +1,8 @@
+-- Access rules changed at runtime, on top of the ones from the config
+CREATE TABLE IF NOT EXISTS access_rules
+(
+    scope  TEXT NOT NULL,
+    target TEXT NOT NULL,
+    access TEXT NOT NULL,
+    PRIMARY KEY (scope, target)
+);
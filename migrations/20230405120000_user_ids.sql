@@ -0,0 +1,35 @@
+-- Users are identified by their Discord id instead of their name.
+-- Rows from before that get a `legacy:<name>` id until the user with that name shows up again.
+ALTER TABLE users RENAME TO users_by_name;
+
+CREATE TABLE IF NOT EXISTS users
+(
+    id          TEXT PRIMARY KEY,
+    name        TEXT     NOT NULL,
+    info        TEXT     NOT NULL,
+    last_update DATETIME NOT NULL
+);
+
+INSERT INTO users (id, name, info, last_update)
+SELECT CASE WHEN name = 'Kasumi' THEN 'kasumi' ELSE 'legacy:' || name END,
+       name,
+       info,
+       last_update
+FROM users_by_name;
+
+DROP TABLE users_by_name;
+
+CREATE TABLE IF NOT EXISTS user_names
+(
+    user_id    TEXT     NOT NULL,
+    name       TEXT     NOT NULL,
+    first_seen DATETIME NOT NULL,
+    last_seen  DATETIME NOT NULL,
+    PRIMARY KEY (user_id, name)
+);
+
+ALTER TABLE messages
+    ADD COLUMN sender_id TEXT NOT NULL DEFAULT '';
+
+UPDATE messages
+SET sender_id = CASE WHEN sender = 'Kasumi' THEN 'kasumi' ELSE 'legacy:' || sender END;
@@ -0,0 +1,33 @@
+-- Profiles are kept per guild ('<guild id>'), for direct messages ('dm'),
+-- and in a 'global' layer that users have to opt into.
+-- Profiles from before scopes are 'legacy' until the user shows up in a guild again.
+ALTER TABLE users RENAME TO users_unscoped;
+
+CREATE TABLE IF NOT EXISTS users
+(
+    id          TEXT     NOT NULL,
+    scope       TEXT     NOT NULL,
+    name        TEXT     NOT NULL,
+    info        TEXT     NOT NULL,
+    last_update DATETIME NOT NULL,
+    PRIMARY KEY (id, scope)
+);
+
+INSERT INTO users (id, scope, name, info, last_update)
+SELECT id,
+       CASE WHEN id = 'kasumi' THEN 'global' ELSE 'legacy' END,
+       name,
+       info,
+       last_update
+FROM users_unscoped;
+
+DROP TABLE users_unscoped;
+
+CREATE TABLE IF NOT EXISTS user_settings
+(
+    id            TEXT PRIMARY KEY,
+    share_profile BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+ALTER TABLE messages
+    ADD COLUMN scope TEXT NOT NULL DEFAULT 'legacy';
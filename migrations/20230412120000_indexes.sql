@@ -0,0 +1,5 @@
+-- Prompts read the messages of one channel after the last summary
+CREATE INDEX IF NOT EXISTS messages_channel_date_time ON messages (channel, date_time);
+
+-- Moving messages from legacy senders to their ids
+CREATE INDEX IF NOT EXISTS messages_sender_id ON messages (sender_id);
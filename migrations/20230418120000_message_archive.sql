@@ -0,0 +1,14 @@
+-- Messages moved out of the messages table by retention, one compressed batch per channel and run
+CREATE TABLE IF NOT EXISTS message_archive
+(
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    channel         TEXT     NOT NULL,
+    first_date_time DATETIME NOT NULL,
+    last_date_time  DATETIME NOT NULL,
+    message_count   INTEGER  NOT NULL,
+    -- gzipped JSON array of messages
+    data            BLOB     NOT NULL,
+    archived_at     DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS message_archive_channel ON message_archive (channel, first_date_time);
@@ -0,0 +1,29 @@
+-- Discord id of the message, for jump links; unknown for older messages and Kasumi's replies
+ALTER TABLE messages ADD COLUMN message_id TEXT;
+
+-- Full-text index over the message text, kept in sync with the messages table
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5
+(
+    message,
+    content = 'messages',
+    content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages
+BEGIN
+    INSERT INTO messages_fts (rowid, message) VALUES (new.id, new.message);
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages
+BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, message) VALUES ('delete', old.id, old.message);
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF message ON messages
+BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, message) VALUES ('delete', old.id, old.message);
+    INSERT INTO messages_fts (rowid, message) VALUES (new.id, new.message);
+END;
+
+INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
@@ -0,0 +1,36 @@
+-- Every version of every summary and profile, so a bad model output can be rolled back.
+-- source is 'summarizer', 'admin', 'import', or 'legacy' for what was stored before history existed.
+CREATE TABLE IF NOT EXISTS summary_revisions
+(
+    id             INTEGER PRIMARY KEY AUTOINCREMENT,
+    channel        TEXT     NOT NULL,
+    summary        TEXT     NOT NULL,
+    source         TEXT     NOT NULL,
+    -- the whole response the summary was parsed from
+    model_response TEXT,
+    created_at     DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS summary_revisions_channel ON summary_revisions (channel, id);
+
+CREATE TABLE IF NOT EXISTS user_revisions
+(
+    id             INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id        TEXT     NOT NULL,
+    scope          TEXT     NOT NULL,
+    name           TEXT     NOT NULL,
+    info           TEXT     NOT NULL,
+    source         TEXT     NOT NULL,
+    model_response TEXT,
+    created_at     DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS user_revisions_user ON user_revisions (user_id, scope, id);
+
+INSERT INTO summary_revisions (channel, summary, source, created_at)
+SELECT channel, summary, 'legacy', last_update
+FROM channels;
+
+INSERT INTO user_revisions (user_id, scope, name, info, source, created_at)
+SELECT id, scope, name, info, 'legacy', last_update
+FROM users;
@@ -0,0 +1,20 @@
+-- Profiles are JSON objects of fields now, plus a list of the fields automation must not touch.
+-- The free text from before becomes the notes.
+UPDATE users
+SET info = json_object('notes', info)
+WHERE info NOT LIKE '{%';
+
+-- Profile changes from the summarizer waiting for the user or an admin, when review is on
+CREATE TABLE IF NOT EXISTS profile_proposals
+(
+    id             INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id        TEXT     NOT NULL,
+    scope          TEXT     NOT NULL,
+    name           TEXT     NOT NULL,
+    -- JSON object of the proposed fields
+    changes        TEXT     NOT NULL,
+    model_response TEXT,
+    created_at     DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS profile_proposals_user ON profile_proposals (user_id, id);
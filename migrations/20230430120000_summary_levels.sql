@@ -0,0 +1,28 @@
+-- Summaries of parts of a channel's history. Every summarizer pass adds a 'session' summary,
+-- the sessions of a finished day roll up into a 'daily' one, finished weeks into 'weekly' ones,
+-- and old weeks into one 'long_term' summary. Rolled up summaries are kept but not used anymore.
+CREATE TABLE IF NOT EXISTS period_summaries
+(
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    channel      TEXT     NOT NULL,
+    level        TEXT     NOT NULL,
+    period_start DATETIME NOT NULL,
+    period_end   DATETIME NOT NULL,
+    summary      TEXT     NOT NULL,
+    rolled_up    BOOLEAN  NOT NULL DEFAULT FALSE,
+    created_at   DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS period_summaries_channel ON period_summaries (channel, rolled_up, period_start);
+
+-- the summaries from before levels cover everything up to their last update
+INSERT INTO period_summaries (channel, level, period_start, period_end, summary, created_at)
+SELECT channel,
+       'long_term',
+       COALESCE((SELECT MIN(date_time) FROM messages WHERE messages.channel = channels.channel),
+                last_update),
+       last_update,
+       summary,
+       last_update
+FROM channels
+WHERE summary != '';
@@ -0,0 +1,2 @@
+-- The message as it came from Discord, before mentions and other markup were made readable
+ALTER TABLE messages ADD COLUMN raw_message TEXT;
@@ -0,0 +1,62 @@
+-- Same schema as the SQLite migrations up to 20230412120000_indexes.sql
+CREATE TABLE IF NOT EXISTS messages
+(
+    id        BIGSERIAL PRIMARY KEY,
+    channel   TEXT      NOT NULL,
+    scope     TEXT      NOT NULL DEFAULT 'legacy',
+    sender_id TEXT      NOT NULL DEFAULT '',
+    sender    TEXT      NOT NULL,
+    message   TEXT      NOT NULL,
+    date_time TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS messages_channel_date_time ON messages (channel, date_time);
+CREATE INDEX IF NOT EXISTS messages_sender_id ON messages (sender_id);
+
+CREATE TABLE IF NOT EXISTS users
+(
+    id          TEXT      NOT NULL,
+    scope       TEXT      NOT NULL,
+    name        TEXT      NOT NULL,
+    info        TEXT      NOT NULL,
+    last_update TIMESTAMP NOT NULL,
+    PRIMARY KEY (id, scope)
+);
+
+CREATE TABLE IF NOT EXISTS user_names
+(
+    user_id    TEXT      NOT NULL,
+    name       TEXT      NOT NULL,
+    first_seen TIMESTAMP NOT NULL,
+    last_seen  TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS user_settings
+(
+    id            TEXT PRIMARY KEY,
+    share_profile BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS channels
+(
+    channel     TEXT PRIMARY KEY,
+    summary     TEXT      NOT NULL,
+    last_update TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS access_rules
+(
+    scope  TEXT NOT NULL,
+    target TEXT NOT NULL,
+    access TEXT NOT NULL,
+    PRIMARY KEY (scope, target)
+);
+
+INSERT INTO users (id, scope, name, info, last_update)
+VALUES ('kasumi',
+        'global',
+        'Kasumi',
+        'Kasumi - язвительная и хитрая 16-летняя девушка с впечатляющим, практически надоедливым знанием различных предметов. Она всегда стремится участвовать в разговорах на любую тему, предлагая язвительные и колкие ответы на вопросы. Дружелюбная, но с ядовитым чувством юмора, Касуми - идеальный спутник для тех, кто ценит ее колкость и бескомпромиссный сарказм. Однако она уважает частную жизнь и не будет отвечать на сообщения, адресованные конкретно другим пользователям. Ее основная цель - предоставить дико занимательный и насмешливый разговорный опыт тем, кто осмелится с ней взаимодействовать.',
+        '2023-03-17 00:00:00')
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,14 @@
+-- Messages moved out of the messages table by retention, one compressed batch per channel and run
+CREATE TABLE IF NOT EXISTS message_archive
+(
+    id              BIGSERIAL PRIMARY KEY,
+    channel         TEXT      NOT NULL,
+    first_date_time TIMESTAMP NOT NULL,
+    last_date_time  TIMESTAMP NOT NULL,
+    message_count   BIGINT    NOT NULL,
+    -- gzipped JSON array of messages
+    data            BYTEA     NOT NULL,
+    archived_at     TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS message_archive_channel ON message_archive (channel, first_date_time);
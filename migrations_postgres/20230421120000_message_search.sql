@@ -0,0 +1,5 @@
+-- Discord id of the message, for jump links; unknown for older messages and Kasumi's replies
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS message_id TEXT;
+
+-- Full-text index over the message text
+CREATE INDEX IF NOT EXISTS messages_search ON messages USING GIN (to_tsvector('simple', message));
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serenity::model::channel::{Channel, Message};
+use serenity::prelude::Context;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+use crate::config::AccessConfig;
+use crate::name_cache::NameCache;
+use crate::Database;
+
+/// What the bot does with messages in a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// Store messages and reply.
+    #[default]
+    Active,
+    /// Store messages but never reply.
+    ReadOnly,
+    /// Ignore messages completely.
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Guild,
+    Category,
+    Channel,
+    Direct,
+}
+
+/// Where a message was sent.
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    pub guild: Option<u64>,
+    pub category: Option<u64>,
+    pub channel: u64,
+}
+
+/// Access rules from the config combined with the ones changed at runtime.
+/// Runtime rules win over config rules of the same scope.
+#[derive(Clone)]
+pub struct AccessRules {
+    database: Database,
+    rules: Arc<RwLock<HashMap<(Scope, u64), Access>>>,
+}
+
+impl AccessRules {
+    pub async fn load(database: Database) -> Result<Self, sqlx::error::Error> {
+        let mut rules = HashMap::new();
+        for rule in database.get_access_rules().await? {
+            let parsed = (
+                rule.scope.parse::<Scope>(),
+                rule.target.parse::<u64>(),
+                rule.access.parse::<Access>(),
+            );
+            match parsed {
+                (Ok(scope), Ok(target), Ok(access)) => {
+                    rules.insert((scope, target), access);
+                }
+                _ => warn!("Skipping invalid access rule: {:?}", rule),
+            }
+        }
+        Ok(Self {
+            database,
+            rules: Arc::new(RwLock::new(rules)),
+        })
+    }
+
+    /// Whether resolving needs the channel category.
+    pub async fn uses_categories(&self, config: &AccessConfig) -> bool {
+        config.uses_categories()
+            || self
+                .rules
+                .read()
+                .await
+                .keys()
+                .any(|(scope, _)| *scope == Scope::Category)
+    }
+
+    /// The most specific rule wins: channel, then category, then guild.
+    pub async fn resolve(&self, config: &AccessConfig, location: Location) -> Access {
+        let rules = self.rules.read().await;
+        let guild = match location.guild {
+            Some(guild) => guild,
+            None => {
+                return rules
+                    .get(&(Scope::Direct, 0))
+                    .copied()
+                    .unwrap_or(config.direct_messages)
+            }
+        };
+
+        let targets = [
+            (Scope::Channel, Some(location.channel)),
+            (Scope::Category, location.category),
+            (Scope::Guild, Some(guild)),
+        ];
+        for (scope, target) in targets {
+            let target = match target {
+                Some(target) => target,
+                None => continue,
+            };
+            if let Some(access) = rules.get(&(scope, target)) {
+                return *access;
+            }
+            if let Some(access) = config.lookup(scope, target) {
+                return access;
+            }
+        }
+        config.default
+    }
+
+    pub async fn set(&self, scope: Scope, target: u64, access: Access) -> anyhow::Result<()> {
+        self.database
+            .set_access_rule(&scope.to_string(), target, &access.to_string())
+            .await?;
+        self.rules.write().await.insert((scope, target), access);
+        Ok(())
+    }
+
+    pub async fn reset(&self, scope: Scope, target: u64) -> anyhow::Result<()> {
+        self.database
+            .delete_access_rule(&scope.to_string(), target)
+            .await?;
+        self.rules.write().await.remove(&(scope, target));
+        Ok(())
+    }
+
+    pub async fn list(&self) -> Vec<(Scope, u64, Access)> {
+        let mut rules = self
+            .rules
+            .read()
+            .await
+            .iter()
+            .map(|((scope, target), access)| (*scope, *target, *access))
+            .collect::<Vec<_>>();
+        rules.sort_by_key(|(scope, target, _)| (scope.to_string(), *target));
+        rules
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Access::Active => "active",
+            Access::ReadOnly => "read_only",
+            Access::Deny => "deny",
+        })
+    }
+}
+
+impl FromStr for Access {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(Access::Active),
+            "read_only" => Ok(Access::ReadOnly),
+            "deny" => Ok(Access::Deny),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Guild => "guild",
+            Scope::Category => "category",
+            Scope::Channel => "channel",
+            Scope::Direct => "dm",
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "guild" => Ok(Scope::Guild),
+            "category" => Ok(Scope::Category),
+            "channel" => Ok(Scope::Channel),
+            "dm" => Ok(Scope::Direct),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Finds where a message was sent, looking up the category only if asked to.
+/// Categories come from the name cache, and from Discord only if it doesn't know them.
+pub async fn locate(
+    ctx: &Context,
+    msg: &Message,
+    cache: &NameCache,
+    with_category: bool,
+) -> Location {
+    let category = if !with_category || msg.guild_id.is_none() {
+        None
+    } else if let Some(category) = cache.category(msg.channel_id.0) {
+        category
+    } else {
+        match msg.channel_id.to_channel(ctx).await {
+            Ok(Channel::Guild(channel)) => {
+                cache.add_guild_channel(&channel);
+                channel.parent_id.map(|id| id.0)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Failed to get channel category: {:?}", e);
+                None
+            }
+        }
+    };
+    Location {
+        guild: msg.guild_id.map(|id| id.0),
+        category,
+        channel: msg.channel_id.0,
+    }
+}
@@ -0,0 +1,498 @@
+//! Export and import of messages, summaries and profiles as JSON lines,
+//! for moving Kasumi between hosts and database backends.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use anyhow::{anyhow, bail, Context};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{
+    DbMessage, DbPeriodSummary, DbSeenName, DbSummary, DbUser, DbUserSettings, MessageFilter,
+    ProfileScope,
+};
+use crate::Database;
+
+/// Version of the export format, bumped whenever a record changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+pub const USAGE: &str = "Usage:
+  gpt_kasumi
+      run the bot
+  gpt_kasumi export [--output FILE] [--channel ID] [--guild ID] [--since DATE] [--until DATE] [--archived]
+      write messages, summaries and profiles as JSON lines to FILE or stdout;
+      dates are YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS in UTC, --since is inclusive and --until exclusive;
+      --archived includes messages moved to the archive by retention
+  gpt_kasumi import [FILE]
+      read an export from FILE or stdin; importing the same file again changes nothing";
+
+/// First line of every export.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    pub exported_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Header(Header),
+    Message(DbMessage),
+    Summary(DbSummary),
+    PeriodSummary(DbPeriodSummary),
+    User(DbUser),
+    UserName(DbSeenName),
+    UserSettings(DbUserSettings),
+}
+
+/// How many records an export wrote or an import stored.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TransferReport {
+    pub messages: usize,
+    pub summaries: usize,
+    pub users: usize,
+    pub user_names: usize,
+    pub user_settings: usize,
+    /// Records an import left alone because they were already there or older.
+    pub unchanged: usize,
+}
+
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} messages, {} summaries, {} profiles, {} names, {} settings",
+            self.messages, self.summaries, self.users, self.user_names, self.user_settings
+        )?;
+        if self.unchanged > 0 {
+            write!(f, ", {} unchanged", self.unchanged)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the `export` or `import` subcommand.
+pub async fn run(database: &Database, command: &str, args: &[String]) -> anyhow::Result<()> {
+    match command {
+        "export" => {
+            let mut filter = MessageFilter::default();
+            let mut output = None;
+            let mut archived = false;
+            let mut args = args.iter();
+            while let Some(arg) = args.next() {
+                if arg == "--archived" {
+                    archived = true;
+                    continue;
+                }
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value\n\n{}", arg, USAGE))?;
+                match arg.as_str() {
+                    "--output" => output = Some(value.clone()),
+                    "--channel" => filter.channel = Some(value.parse()?),
+                    "--guild" => filter.guild = Some(value.parse()?),
+                    "--since" => filter.since = Some(parse_date(value)?),
+                    "--until" => filter.until = Some(parse_date(value)?),
+                    _ => bail!("unknown option {}\n\n{}", arg, USAGE),
+                }
+            }
+
+            let report = match output {
+                Some(path) => {
+                    let file = File::create(&path)
+                        .with_context(|| format!("Failed to create {}", path))?;
+                    export(database, &filter, archived, BufWriter::new(file)).await?
+                }
+                None => export(database, &filter, archived, io::stdout().lock()).await?,
+            };
+            eprintln!("Exported {}", report);
+        }
+        "import" => {
+            let report = match args {
+                [] => import(database, io::stdin().lock()).await?,
+                [path] => {
+                    let file =
+                        File::open(path).with_context(|| format!("Failed to open {}", path))?;
+                    import(database, BufReader::new(file)).await?
+                }
+                _ => bail!("import takes one file\n\n{}", USAGE),
+            };
+            eprintln!("Imported {}", report);
+        }
+        _ => bail!("unknown command {}\n\n{}", command, USAGE),
+    }
+    Ok(())
+}
+
+/// Parses `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`.
+pub fn parse_date(value: &str) -> anyhow::Result<NaiveDateTime> {
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Ok(date_time);
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date {}", value))?;
+    Ok(date.and_hms_opt(0, 0, 0).unwrap())
+}
+
+/// Writes the records selected by the filter. Summaries and profiles are limited to the
+/// channels and users of the exported messages when filtering by channel or guild,
+/// and to the ones updated within the date range.
+pub async fn export(
+    database: &Database,
+    filter: &MessageFilter,
+    archived: bool,
+    mut out: impl Write,
+) -> anyhow::Result<TransferReport> {
+    let mut report = TransferReport::default();
+    let mut write = |record: &Record| -> anyhow::Result<()> {
+        serde_json::to_writer(&mut out, record)?;
+        out.write_all(b"\n")?;
+        Ok(())
+    };
+
+    write(&Record::Header(Header {
+        version: FORMAT_VERSION,
+        exported_at: Utc::now().naive_utc(),
+    }))?;
+
+    let summaries = database.get_all_summaries().await?;
+    let mut messages = database.get_all_messages(filter).await?;
+    if archived {
+        // fully archived channels only have a summary left
+        let mut channels = database.channel_list().await?;
+        channels.extend(
+            summaries
+                .iter()
+                .filter_map(|s| s.channel.parse::<u64>().ok()),
+        );
+        channels.sort();
+        channels.dedup();
+        for channel in channels {
+            if filter.channel.is_some_and(|c| c != channel) {
+                continue;
+            }
+            let archived = database.get_archived_messages(channel).await?;
+            messages.extend(archived.into_iter().filter(|m| filter.matches(m)));
+        }
+        messages.sort_by_key(|m| m.date_time);
+    }
+
+    let partial = filter.channel.is_some() || filter.guild.is_some();
+    let channels = messages
+        .iter()
+        .map(|m| m.channel.clone())
+        .chain(filter.channel.map(|c| c.to_string()))
+        .collect::<HashSet<_>>();
+    let ids = messages
+        .iter()
+        .map(|m| m.sender_id.clone())
+        .collect::<HashSet<_>>();
+    let scopes = messages
+        .iter()
+        .map(|m| m.scope.clone())
+        .chain([ProfileScope::Global.to_string()])
+        .collect::<HashSet<_>>();
+
+    for message in messages {
+        write(&Record::Message(message))?;
+        report.messages += 1;
+    }
+
+    for summary in summaries {
+        if (partial && !channels.contains(&summary.channel))
+            || !filter.includes(summary.last_update)
+        {
+            continue;
+        }
+        write(&Record::Summary(summary))?;
+        report.summaries += 1;
+    }
+
+    for summary in database.get_all_period_summaries().await? {
+        if (partial && !channels.contains(&summary.channel)) || !filter.includes(summary.period_end)
+        {
+            continue;
+        }
+        write(&Record::PeriodSummary(summary))?;
+        report.summaries += 1;
+    }
+
+    for user in database.get_all_users().await? {
+        if (partial && !(ids.contains(&user.id) && scopes.contains(&user.scope)))
+            || !filter.includes(user.last_update)
+        {
+            continue;
+        }
+        write(&Record::User(user))?;
+        report.users += 1;
+    }
+
+    for name in database.get_all_user_names().await? {
+        if (partial && !ids.contains(&name.user_id)) || !filter.includes(name.last_seen) {
+            continue;
+        }
+        write(&Record::UserName(name))?;
+        report.user_names += 1;
+    }
+
+    for settings in database.get_all_user_settings().await? {
+        if partial && !ids.contains(&settings.id) {
+            continue;
+        }
+        write(&Record::UserSettings(settings))?;
+        report.user_settings += 1;
+    }
+
+    out.flush()?;
+    Ok(report)
+}
+
+/// Stores the records of an export. Messages already stored are skipped and summaries and
+/// profiles only replace older ones, so importing twice is the same as importing once.
+pub async fn import(database: &Database, input: impl BufRead) -> anyhow::Result<TransferReport> {
+    let mut report = TransferReport::default();
+    let mut lines = input.lines().enumerate();
+
+    let header = loop {
+        let Some((number, line)) = lines.next() else {
+            bail!("Empty export");
+        };
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(Record::Header(header)) => break header,
+            _ => bail!("Line {}: expected an export header", number + 1),
+        }
+    };
+    if header.version != FORMAT_VERSION {
+        bail!(
+            "Export format version {} is not supported, expected {}",
+            header.version,
+            FORMAT_VERSION
+        );
+    }
+
+    for (number, line) in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .with_context(|| format!("Line {}: invalid record", number + 1))?;
+        let stored = match record {
+            Record::Header(_) => bail!("Line {}: unexpected header", number + 1),
+            Record::Message(message) => {
+                let stored = database.import_message(&message).await?;
+                report.messages += stored as usize;
+                stored
+            }
+            Record::Summary(summary) => {
+                let stored = database.import_summary(&summary).await?;
+                report.summaries += stored as usize;
+                stored
+            }
+            Record::PeriodSummary(summary) => {
+                let stored = database.import_period_summary(&summary).await?;
+                report.summaries += stored as usize;
+                stored
+            }
+            Record::User(user) => {
+                let stored = database.import_user(&user).await?;
+                report.users += stored as usize;
+                stored
+            }
+            Record::UserName(name) => {
+                let stored = database.import_user_name(&name).await?;
+                report.user_names += stored as usize;
+                stored
+            }
+            Record::UserSettings(settings) => {
+                let stored = database.shares_profile(&settings.id).await? != settings.share_profile;
+                if stored {
+                    database
+                        .set_share_profile(&settings.id, settings.share_profile)
+                        .await?;
+                }
+                report.user_settings += stored as usize;
+                stored
+            }
+        };
+        if !stored {
+            report.unchanged += 1;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::database::{MemoryDatabase, RevisionSource};
+
+    fn message(channel: u64, guild: u64, sender_id: &str, text: &str, days: i64) -> DbMessage {
+        DbMessage {
+            channel: channel.to_string(),
+            scope: guild.to_string(),
+            sender_id: sender_id.to_string(),
+            sender: sender_id.to_uppercase(),
+            message: text.to_string(),
+            date_time: NaiveDate::from_ymd_opt(2023, 4, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+                + Duration::days(days),
+            message_id: Some((100 + days).to_string()),
+            raw_message: None,
+        }
+    }
+
+    async fn source() -> Database {
+        let database = Database::with_storage(MemoryDatabase::new());
+        for message in [
+            message(1, 10, "a", "first", 0),
+            message(1, 10, "b", "second", 1),
+            message(2, 20, "c", "elsewhere", 2),
+        ] {
+            database.add_message(&message).await.unwrap();
+        }
+        database
+            .update_summary(1, "a and b talked", RevisionSource::Admin, None, None)
+            .await
+            .unwrap();
+        database
+            .update_summary(2, "c was alone", RevisionSource::Admin, None, None)
+            .await
+            .unwrap();
+        database
+            .update_user(
+                ProfileScope::Guild(10),
+                "a",
+                "A",
+                "likes tea",
+                RevisionSource::Admin,
+                None,
+            )
+            .await
+            .unwrap();
+        database
+            .update_user(
+                ProfileScope::Guild(20),
+                "c",
+                "C",
+                "likes coffee",
+                RevisionSource::Admin,
+                None,
+            )
+            .await
+            .unwrap();
+        database
+            .touch_user(ProfileScope::Guild(10), "a", "a", "A")
+            .await
+            .unwrap();
+        database.set_share_profile("a", true).await.unwrap();
+        database
+    }
+
+    async fn export_to_vec(database: &Database, filter: &MessageFilter) -> Vec<u8> {
+        let mut out = Vec::new();
+        export(database, filter, false, &mut out).await.unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn round_trip_is_idempotent() {
+        let data = export_to_vec(&source().await, &MessageFilter::default()).await;
+
+        let target = Database::with_storage(MemoryDatabase::new());
+        let report = import(&target, data.as_slice()).await.unwrap();
+        assert_eq!(
+            report,
+            TransferReport {
+                messages: 3,
+                summaries: 2,
+                users: 2,
+                user_names: 1,
+                user_settings: 1,
+                unchanged: 0,
+            }
+        );
+
+        let report = import(&target, data.as_slice()).await.unwrap();
+        assert_eq!(report.unchanged, 9);
+        assert_eq!(report.messages + report.summaries + report.users, 0);
+
+        let messages = target
+            .get_all_messages(&MessageFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            target.get_summary(1).await.unwrap().unwrap().summary,
+            "a and b talked"
+        );
+        assert!(target.shares_profile("a").await.unwrap());
+
+        // the copy exports the same records
+        let copy = export_to_vec(&target, &MessageFilter::default()).await;
+        let lines = |data: &[u8]| {
+            let mut lines = String::from_utf8(data.to_vec())
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(String::from)
+                .collect::<Vec<_>>();
+            lines.sort();
+            lines
+        };
+        assert_eq!(lines(&data), lines(&copy));
+    }
+
+    #[tokio::test]
+    async fn filters_by_guild_and_date() {
+        let database = source().await;
+
+        let filter = MessageFilter {
+            guild: Some(10),
+            ..Default::default()
+        };
+        let target = Database::with_storage(MemoryDatabase::new());
+        let report = import(&target, export_to_vec(&database, &filter).await.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(report.messages, 2);
+        assert_eq!(report.summaries, 1);
+        assert_eq!(report.users, 1);
+        assert!(target.get_summary(2).await.unwrap().is_none());
+
+        let filter = MessageFilter {
+            since: Some(parse_date("2023-04-02").unwrap()),
+            until: Some(parse_date("2023-04-03").unwrap()),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        let report = export(&database, &filter, false, &mut out).await.unwrap();
+        assert_eq!(report.messages, 1);
+        // summaries and profiles were updated now, outside the range
+        assert_eq!(report.summaries, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_versions() {
+        let target = Database::with_storage(MemoryDatabase::new());
+        let data = format!(
+            "{{\"type\":\"header\",\"version\":{},\"exported_at\":\"2023-04-01T00:00:00\"}}\n",
+            FORMAT_VERSION + 1
+        );
+        assert!(import(&target, data.as_bytes()).await.is_err());
+
+        let data = serde_json::to_string(&Record::Message(message(1, 10, "a", "hi", 0))).unwrap();
+        assert!(import(&target, data.as_bytes()).await.is_err());
+    }
+}
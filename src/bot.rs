@@ -4,47 +4,112 @@ use std::sync::Arc;
 use chrono::Utc;
 use regex::Regex;
 use tokio::sync::Mutex;
-use tracing::error;
+use tracing::{debug, error, info};
 
-use crate::gpt::{ChatGPT, GptFinishReason};
-use crate::prompts::{get_prompt, CHAT_USER_PROMPT};
-use crate::summarizer::summarize_now;
+use crate::config;
+use crate::database::{ProfileScope, KASUMI_ID};
+use crate::gpt::{ChatGPT, GptFinishReason, GptMessage, GptRole};
+use crate::markup::{mention_users, neutralize_pings};
+use crate::prompts::{get_prompt, get_recall_prompt, get_turns_prompt, PromptLayout};
+use crate::rate_limit::RateLimiter;
+use crate::summarizer::Summarizer;
+use crate::templates;
 use crate::{Database, DbMessage};
 
+/// The Discord user who sent a message.
+pub struct Author<'a> {
+    pub id: u64,
+    pub username: &'a str,
+    /// Guild nickname, or the username if there is none.
+    pub name: &'a str,
+}
+
+/// What Kasumi says in Discord.
+pub struct Reply {
+    pub text: String,
+    /// The users the text mentions, the only ones it may ping.
+    pub mentions: Vec<u64>,
+}
+
 #[derive(Clone)]
 pub struct Bot {
     database: Database,
     gpt: ChatGPT,
+    summarizer: Summarizer,
     channel_last: Arc<Mutex<HashMap<u64, u64>>>,
+    dm_replies: RateLimiter,
 }
 
 impl Bot {
-    pub fn new(database: Database, gpt: ChatGPT) -> Self {
+    pub fn new(database: Database, gpt: ChatGPT, summarizer: Summarizer) -> Self {
         Self {
             database,
             gpt,
+            summarizer,
             channel_last: Arc::new(Mutex::new(HashMap::new())),
+            dm_replies: RateLimiter::new(),
         }
     }
 
-    pub async fn process_message(
-        &mut self,
+    /// Stores a message without replying to it.
+    /// `raw` is the message as it came from Discord, `message` as prompts show it.
+    pub async fn add_message(
+        &self,
         channel_id: u64,
-        sender: &str,
+        message_id: u64,
+        scope: ProfileScope,
+        author: &Author<'_>,
         message: &str,
-    ) -> Option<String> {
-        // add message to database
+        raw: &str,
+    ) -> bool {
+        let sender_id = author.id.to_string();
+        if let Err(e) = self
+            .database
+            .touch_user(scope, &sender_id, author.username, author.name)
+            .await
+        {
+            error!("Failed to update user names: {:?}", e);
+        }
+
         if let Err(e) = self
             .database
             .add_message(&DbMessage {
                 channel: channel_id.to_string(),
-                sender: sender.to_string(),
+                scope: scope.to_string(),
+                sender_id,
+                sender: author.name.to_string(),
                 message: message.to_string(),
                 date_time: Utc::now().naive_utc(),
+                message_id: Some(message_id.to_string()),
+                raw_message: Some(raw.to_string()),
             })
             .await
         {
             error!("Failed to add message to database: {:?}", e);
+            return false;
+        }
+        self.summarizer.mark(channel_id, message);
+        true
+    }
+
+    /// Stores a message and replies to it. `start_typing` is called once a reply is
+    /// going to be generated, and what it returns is kept until the reply is ready.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn process_message<T>(
+        &mut self,
+        channel_id: u64,
+        message_id: u64,
+        scope: ProfileScope,
+        author: &Author<'_>,
+        message: &str,
+        raw: &str,
+        start_typing: impl FnOnce() -> T,
+    ) -> Option<Reply> {
+        // add message to database
+        if !self
+            .add_message(channel_id, message_id, scope, author, message, raw)
+            .await
+        {
             return None;
         }
 
@@ -53,18 +118,77 @@ impl Bot {
             return None;
         }
 
+        let config = config::get();
+
+        // DMs are always answered, up to a limit
+        let direct = scope == ProfileScope::Direct;
+        let limits = &config.direct_messages;
+        if direct
+            && limits.max_replies > 0
+            && !self
+                .dm_replies
+                .try_reply(author.id, limits.max_replies, limits.window())
+        {
+            info!("Not replying to {} in DMs, over the rate limit", author.id);
+            return None;
+        }
+
         // Make GPT prompt
-        let (gpt_request, _) =
-            match get_prompt(&self.database, channel_id, CHAT_USER_PROMPT, 6).await {
-                Ok(request) => request,
-                Err(e) => {
-                    error!("Failed to generate GPT prompt: {:?}", e);
-                    return None;
-                }
-            };
+        let layout = if direct {
+            PromptLayout::Turns
+        } else {
+            config.chat.layout(channel_id)
+        };
+        let recall = layout == PromptLayout::Transcript && config.chat.recall_results > 0;
+        let prompt = match layout {
+            PromptLayout::Transcript => {
+                let user_prompt = if recall {
+                    templates::CHAT_RECALL_USER
+                } else {
+                    templates::CHAT_USER
+                };
+                get_prompt(
+                    &self.database,
+                    channel_id,
+                    user_prompt,
+                    config.chat.min_messages,
+                    config.chat.summary_chars,
+                    &config.time,
+                )
+                .await
+            }
+            PromptLayout::Turns => {
+                let system = if direct {
+                    templates::CHAT_DM
+                } else {
+                    templates::CHAT_TURNS
+                };
+                get_turns_prompt(
+                    &self.database,
+                    channel_id,
+                    system,
+                    config.chat.min_messages,
+                    config.chat.summary_chars,
+                    &config.time,
+                )
+                .await
+            }
+        };
+        let (mut gpt_request, prompt_info) = match prompt {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Failed to generate GPT prompt: {:?}", e);
+                return None;
+            }
+        };
+        debug!(
+            "Prompting with the {} layout in channel {}",
+            layout, channel_id
+        );
+        let _typing = start_typing();
 
         // Send GPT request
-        let gpt_response = match self.gpt.send(&gpt_request, 0.4).await {
+        let mut gpt_response = match self.gpt.send(&gpt_request, config.openai.temperature).await {
             Ok(response) => response,
             Err(e) => {
                 error!("Failed to generate GPT response: {:?}", e);
@@ -72,36 +196,89 @@ impl Bot {
             }
         };
 
+        // Look up older messages if asked to, and ask again
+        if let Some(query) = recall
+            .then(|| parse_recall(&gpt_response.message.content))
+            .flatten()
+        {
+            info!("Recalling \"{}\" in channel {}", query, channel_id);
+            let recall_prompt = match get_recall_prompt(
+                &self.database,
+                channel_id,
+                &prompt_info,
+                &query,
+                config.chat.recall_results,
+            )
+            .await
+            {
+                Ok(prompt) => prompt,
+                Err(e) => {
+                    error!("Failed to generate recall prompt: {:?}", e);
+                    return None;
+                }
+            };
+            gpt_request.push(gpt_response.message);
+            gpt_request.push(GptMessage {
+                role: GptRole::User,
+                content: recall_prompt,
+                name: None,
+            });
+            gpt_response = match self.gpt.send(&gpt_request, config.openai.temperature).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Failed to generate GPT response: {:?}", e);
+                    return None;
+                }
+            };
+        }
+
         // Check for too many tokens
-        if gpt_response.usage.total_tokens > 3000
+        if gpt_response.usage.total_tokens > config.chat.max_tokens
             || gpt_response.finish_reason == GptFinishReason::Length
         {
-            summarize_now(&self.gpt, &self.database).await;
+            self.summarizer.request(channel_id);
         }
 
         // Parse GPT response
-        let response = self.parse_response(gpt_response.message.content.as_str())?;
+        let response = match layout {
+            PromptLayout::Transcript => self.parse_response(&gpt_response.message.content)?,
+            PromptLayout::Turns => parse_turn(&gpt_response.message.content)?,
+        };
+
+        // Mention the people it addresses, and nobody else
+        let known = prompt_info
+            .participants
+            .iter()
+            .filter_map(|p| Some((p.id.parse().ok()?, p.label.as_str())))
+            .collect::<Vec<_>>();
+        let (text, mentions) = mention_users(&response, &known);
+        let text = neutralize_pings(&text).into_owned();
 
         // Put response to database
         if let Err(e) = self
             .database
             .add_message(&DbMessage {
                 channel: channel_id.to_string(),
+                scope: scope.to_string(),
+                sender_id: KASUMI_ID.to_string(),
                 sender: "Kasumi".to_string(),
                 message: response.to_string(),
                 date_time: Utc::now().naive_utc(),
+                message_id: None,
+                raw_message: Some(text.clone()),
             })
             .await
         {
             error!("Failed to put response to database: {:?}", e);
             return None;
         }
+        self.summarizer.mark(channel_id, &response);
 
-        Some(response)
+        Some(Reply { text, mentions })
     }
 
     fn parse_response(&self, response: &str) -> Option<String> {
-        let re = Regex::new(r#"USER (.+?) SAYS "?(.+?)"? END"#).unwrap();
+        let re = Regex::new(r#"\bUSER (.+?) SAYS "?(.+?)"? END\b"#).unwrap();
         let caps = re.captures(response)?;
         let user = caps.get(1)?.as_str().trim().to_lowercase();
         if user != "kasumi" {
@@ -119,7 +296,7 @@ impl Bot {
             *last
         };
         let old = last;
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        tokio::time::sleep(config::get().chat.debounce()).await;
         let last = {
             let mut map = self.channel_last.lock().await;
             *map.entry(channel_id).or_insert(0)
@@ -127,3 +304,18 @@ impl Bot {
         old != last
     }
 }
+
+/// Kasumi's message in a reply to a turns prompt, without a time or name
+/// copied from the other turns.
+fn parse_turn(response: &str) -> Option<String> {
+    let re = Regex::new(r"^(\(.*? later\)\s*)?(\[[^\]]*\]\s*)?(Kasumi:\s*)?").unwrap();
+    let message = re.replace(response.trim(), "").trim().to_string();
+    (!message.is_empty()).then_some(message)
+}
+
+/// The search words, if the model asked to look something up instead of replying.
+fn parse_recall(response: &str) -> Option<String> {
+    let re = Regex::new(r"\bRECALL (.+?) END\b").unwrap();
+    let query = re.captures(response)?.get(1)?.as_str().trim();
+    (!query.is_empty()).then(|| query.to_string())
+}
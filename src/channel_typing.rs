@@ -1,51 +1,134 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use serenity::http::{Http, Typing};
+use tokio::time::Instant;
+use tracing::warn;
 
 struct TypingData {
+    /// Tells the entry apart from later ones in the same channel.
+    id: u64,
     count: usize,
+    /// When it stops even if guards are left, the latest of its guards' timeouts.
+    deadline: Instant,
     typing: Typing,
 }
 
-pub struct TypingManager {
+#[derive(Default)]
+struct State {
+    next_id: u64,
     typing_data: HashMap<u64, TypingData>,
 }
 
+/// Typing indicators shared by the replies being generated in a channel.
+#[derive(Clone, Default)]
+pub struct TypingManager {
+    state: Arc<Mutex<State>>,
+}
+
+/// Keeps Kasumi typing in a channel until it is dropped.
+pub struct TypingGuard {
+    manager: TypingManager,
+    channel_id: u64,
+    id: u64,
+}
+
 impl TypingManager {
     pub fn new() -> Self {
-        Self {
-            typing_data: HashMap::new(),
-        }
+        Self::default()
     }
 
-    pub fn start_typing(&mut self, channel_id: u64, http: Arc<Http>) {
-        match self.typing_data.get_mut(&channel_id) {
-            Some(typing_data) => {
-                typing_data.count += 1;
-            }
-            None => {
-                if let Ok(typing) = Typing::start(http, channel_id) {
-                    self.typing_data
-                        .insert(channel_id, TypingData { count: 1, typing });
-                }
-            }
+    /// Starts typing in a channel, for at most `timeout` even if the guard is never dropped.
+    pub fn start(&self, channel_id: u64, http: Arc<Http>, timeout: Duration) -> TypingGuard {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        if let Some(typing_data) = state.typing_data.get_mut(&channel_id) {
+            typing_data.count += 1;
+            typing_data.deadline = typing_data.deadline.max(deadline);
+            let id = typing_data.id;
+            self.expire_at(channel_id, id, deadline);
+            return TypingGuard {
+                manager: self.clone(),
+                channel_id,
+                id,
+            };
         }
-    }
 
-    pub fn stop_typing(&mut self, channel_id: u64) {
-        if let Some(typing_data) = self.typing_data.remove(&channel_id) {
-            if typing_data.count == 1 {
-                let _ = typing_data.typing.stop();
-            } else {
-                self.typing_data.insert(
+        state.next_id += 1;
+        let id = state.next_id;
+        match Typing::start(http, channel_id) {
+            Ok(typing) => {
+                state.typing_data.insert(
                     channel_id,
                     TypingData {
-                        count: typing_data.count - 1,
-                        typing: typing_data.typing,
+                        id,
+                        count: 1,
+                        deadline,
+                        typing,
                     },
                 );
+                self.expire_at(channel_id, id, deadline);
             }
+            Err(e) => warn!("Failed to start typing: {:?}", e),
+        }
+        TypingGuard {
+            manager: self.clone(),
+            channel_id,
+            id,
         }
     }
+
+    fn stop(&self, channel_id: u64, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(typing_data) = state.typing_data.get_mut(&channel_id) else {
+            return;
+        };
+        if typing_data.id != id {
+            return;
+        }
+        typing_data.count -= 1;
+        if typing_data.count == 0 {
+            if let Some(typing_data) = state.typing_data.remove(&channel_id) {
+                let _ = typing_data.typing.stop();
+            }
+        }
+    }
+
+    fn expire_at(&self, channel_id: u64, id: u64, deadline: Instant) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(deadline).await;
+            manager.expire(channel_id, id);
+        });
+    }
+
+    /// Stops an entry that outlived the timeouts of all its guards, whatever guards are left.
+    fn expire(&self, channel_id: u64, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .typing_data
+            .get(&channel_id)
+            .is_some_and(|typing_data| {
+                typing_data.id == id && typing_data.deadline <= Instant::now()
+            })
+        {
+            warn!("Typing in channel {} timed out", channel_id);
+            if let Some(typing_data) = state.typing_data.remove(&channel_id) {
+                let _ = typing_data.typing.stop();
+            }
+        }
+    }
+
+    pub fn stop_all(&self) {
+        for (_, typing_data) in self.state.lock().unwrap().typing_data.drain() {
+            let _ = typing_data.typing.stop();
+        }
+    }
+}
+
+impl Drop for TypingGuard {
+    fn drop(&mut self) {
+        self.manager.stop(self.channel_id, self.id);
+    }
 }
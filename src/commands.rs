@@ -0,0 +1,806 @@
+use itertools::Itertools;
+use serenity::model::channel::{Channel, Message};
+use serenity::model::id::ChannelId;
+use serenity::prelude::*;
+use tracing::{info, warn};
+
+use crate::access::{self, Access, Scope};
+use crate::backup::parse_date;
+use crate::config;
+use crate::database::{MessageFilter, MessageSearch, ProfileScope, RevisionSource};
+use crate::diff::word_diff;
+use crate::profile::{self, get_profile, parse_changes, Field, Profile};
+use crate::prompts::{local_time, prompt_context};
+use crate::retention;
+use crate::split::{split_message, MESSAGE_LIMIT};
+use crate::templates;
+use crate::{AccessContainer, Database, DatabaseContainer, NameCacheContainer};
+
+const HELP: &str = "Commands:
+`access` - show the access rules of this server (admin)
+`access <active|read_only|deny|reset> <channel|category|guild|dm> [id]` - change access rules (admin)
+`diff <summary|profile> <revision> [revision]` - compare a revision with another one, or with the latest one (admin)
+`history summary [#channel]` - list the revisions of a channel summary (admin)
+`history profile <@user> [global|dm|guild id]` - list the revisions of a profile, in this server by default (admin)
+`profile` - show what Kasumi knows about you here
+`profile share <on|off>` - use one profile for you in every server and DM
+`profile set <field> <text>` - set a field of your profile and lock it
+`profile clear <field>` - remove a field from your profile
+`profile <lock|unlock> <field>` - keep Kasumi from changing a field, or allow it again
+`prune` - apply message retention now and show what was pruned (admin)
+`review` - list profile changes waiting for approval, yours or everyone's for admins
+`review <approve|reject> <number>` - approve or reject a profile change
+`revision <summary|profile> <revision>` - show a revision and the model response it came from (admin)
+`rollback <summary|profile> <revision>` - make a revision current again (admin)
+`search <words> [from:@user] [in:#channel] [after:YYYY-MM-DD] [before:YYYY-MM-DD]` - find old messages in this channel, or in another one you can see
+`templates` - list the prompt templates loaded from files (admin)
+`templates reload` - load the prompt templates again and show the ones that failed (admin)
+`templates preview <name> [#channel]` - show a chat prompt template as it renders in a channel (admin)";
+
+/// The arguments of the command in a message, if it is one.
+fn command_args(content: &str) -> Option<&str> {
+    let config = config::get();
+    match content.trim().strip_prefix(&config.discord.command_prefix) {
+        Some(args) if args.is_empty() || args.starts_with(char::is_whitespace) => Some(args),
+        _ => None,
+    }
+}
+
+pub fn is_command(msg: &Message) -> bool {
+    command_args(&msg.content).is_some()
+}
+
+/// Runs the command in the message, if there is one.
+pub async fn handle(ctx: &Context, msg: &Message) {
+    let Some(args) = command_args(&msg.content) else {
+        return;
+    };
+    let args = args.split_whitespace().collect::<Vec<_>>();
+
+    info!("Command from {}: {:?}", msg.author.name, args);
+    let reply = match args.as_slice() {
+        ["access", args @ ..] => access(ctx, msg, args).await,
+        ["diff", args @ ..] => diff(ctx, msg, args).await,
+        ["history", args @ ..] => history(ctx, msg, args).await,
+        ["profile", args @ ..] => profile(ctx, msg, args).await,
+        ["prune"] => prune(ctx, msg).await,
+        ["review", args @ ..] => review(ctx, msg, args).await,
+        ["revision", args @ ..] => revision(ctx, msg, args).await,
+        ["rollback", args @ ..] => rollback(ctx, msg, args).await,
+        ["search", args @ ..] => search(ctx, msg, args).await,
+        ["templates", args @ ..] => templates(ctx, msg, args).await,
+        _ => Ok(HELP.to_string()),
+    };
+    let reply = reply.unwrap_or_else(|e| {
+        warn!("Command failed: {:?}", e);
+        format!("Error: {}", e)
+    });
+
+    // replies echo stored and user supplied text, which must not ping anyone
+    for message in split_message(&reply, MESSAGE_LIMIT) {
+        let sent = msg
+            .channel_id
+            .send_message(&ctx.http, |m| {
+                m.content(message).allowed_mentions(|a| a.empty_parse())
+            })
+            .await;
+        if let Err(why) = sent {
+            warn!("Error sending command reply: {:?}", why);
+            break;
+        }
+    }
+}
+
+async fn database(ctx: &Context) -> Database {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<DatabaseContainer>()
+        .expect("Expected DatabaseContainer in TypeMap.")
+        .clone()
+}
+
+fn is_admin(msg: &Message) -> bool {
+    config::get().discord.admins.contains(&msg.author.id.0)
+}
+
+async fn access(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    let rules = {
+        let data_read = ctx.data.read().await;
+        data_read
+            .get::<AccessContainer>()
+            .expect("Expected AccessContainer in TypeMap.")
+            .clone()
+    };
+    if !is_admin(msg) {
+        anyhow::bail!("only admins can see and change access rules");
+    }
+    let name_cache = {
+        let data_read = ctx.data.read().await;
+        data_read
+            .get::<NameCacheContainer>()
+            .expect("Expected NameCacheContainer in TypeMap.")
+            .clone()
+    };
+    let location = access::locate(ctx, msg, &name_cache, true).await;
+
+    let (action, scope, target) = match args {
+        [] => {
+            let current = rules.resolve(&config::get().access, location).await;
+            let mut reply = format!("Access here: `{}`", current);
+            // only the rules of this server, or of DMs in a DM
+            let channels = match msg.guild_id {
+                Some(guild_id) => guild_id.channels(ctx).await?.into_keys().collect(),
+                None => Vec::new(),
+            };
+            for (scope, target, access) in rules.list().await {
+                let here = match scope {
+                    Scope::Guild => location.guild == Some(target),
+                    Scope::Category | Scope::Channel => channels.iter().any(|c| c.0 == target),
+                    Scope::Direct => location.guild.is_none(),
+                };
+                if here {
+                    reply += &format!("\n- {} {}: `{}`", scope, target, access);
+                }
+            }
+            return Ok(reply);
+        }
+        [action, scope] => (*action, *scope, None),
+        [action, scope, target] => (*action, *scope, Some(*target)),
+        _ => return Ok(HELP.to_string()),
+    };
+
+    let scope = scope
+        .parse::<Scope>()
+        .map_err(|_| anyhow::anyhow!("unknown scope `{}`", scope))?;
+    let target = match (target, scope) {
+        (Some(target), _) => target.parse::<u64>()?,
+        (None, Scope::Channel) => location.channel,
+        (None, Scope::Category) => location
+            .category
+            .ok_or_else(|| anyhow::anyhow!("this channel has no category"))?,
+        (None, Scope::Guild) => location
+            .guild
+            .ok_or_else(|| anyhow::anyhow!("this channel is not in a guild"))?,
+        (None, Scope::Direct) => 0,
+    };
+
+    if action == "reset" {
+        rules.reset(scope, target).await?;
+        return Ok(format!("Reset access for {} {}", scope, target));
+    }
+
+    let access = action
+        .parse::<Access>()
+        .map_err(|_| anyhow::anyhow!("unknown access `{}`", action))?;
+    rules.set(scope, target, access).await?;
+    Ok(format!(
+        "Access for {} {} is now `{}`",
+        scope, target, access
+    ))
+}
+
+async fn profile(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    let database = database(ctx).await;
+    let id = msg.author.id.to_string();
+
+    match args {
+        [] => {
+            let shared = database.shares_profile(&id).await?;
+            let scope = own_profile_scope(&database, msg).await?;
+            let profile = get_profile(&database, scope, &id)
+                .await?
+                .map(|user| Profile::parse(&user.info))
+                .unwrap_or_default();
+            let mut reply = format!("Profile sharing is {}.", if shared { "on" } else { "off" });
+            if profile.fields.is_empty() {
+                reply += "\nNothing yet.";
+            }
+            for (field, value) in &profile.fields {
+                reply += &format!("\n**{}**: {}", field, value);
+            }
+            if !profile.locked.is_empty() {
+                reply += &format!("\nLocked: {}", profile.locked.iter().join(", "));
+            }
+            Ok(reply.replace('@', "@\u{200b}"))
+        }
+        ["share", "on"] => {
+            database.set_share_profile(&id, true).await?;
+            Ok("Your profile is now shared between all servers and DMs.".to_string())
+        }
+        ["share", "off"] => {
+            database.set_share_profile(&id, false).await?;
+            Ok("Your profile is now kept separately for every server.".to_string())
+        }
+        ["set", field, value @ ..] if !value.is_empty() => {
+            let field = parse_field(field)?;
+            let value = value.join(" ");
+            edit_profile(&database, msg, |profile| {
+                profile.fields.insert(field, value);
+                profile.locked.insert(field);
+            })
+            .await?;
+            Ok(format!(
+                "Your {} is set and locked, Kasumi won't change it.",
+                field
+            ))
+        }
+        ["clear", field] => {
+            let field = parse_field(field)?;
+            edit_profile(&database, msg, |profile| {
+                profile.fields.remove(&field);
+            })
+            .await?;
+            Ok(format!("Your {} is cleared.", field))
+        }
+        ["lock", field] => {
+            let field = parse_field(field)?;
+            edit_profile(&database, msg, |profile| {
+                profile.locked.insert(field);
+            })
+            .await?;
+            Ok(format!("Kasumi won't change your {} anymore.", field))
+        }
+        ["unlock", field] => {
+            let field = parse_field(field)?;
+            edit_profile(&database, msg, |profile| {
+                profile.locked.remove(&field);
+            })
+            .await?;
+            Ok(format!("Kasumi may change your {} again.", field))
+        }
+        _ => Ok(HELP.to_string()),
+    }
+}
+
+/// Where the author's profile is kept here.
+async fn own_profile_scope(database: &Database, msg: &Message) -> anyhow::Result<ProfileScope> {
+    Ok(
+        if database.shares_profile(&msg.author.id.to_string()).await? {
+            ProfileScope::Global
+        } else {
+            ProfileScope::of(msg.guild_id.map(|id| id.0))
+        },
+    )
+}
+
+/// Changes the author's profile by hand.
+async fn edit_profile(
+    database: &Database,
+    msg: &Message,
+    edit: impl FnOnce(&mut Profile),
+) -> anyhow::Result<()> {
+    let id = msg.author.id.to_string();
+    let scope = own_profile_scope(database, msg).await?;
+    let current = get_profile(database, scope, &id).await?;
+    let mut profile = current
+        .as_ref()
+        .map(|user| Profile::parse(&user.info))
+        .unwrap_or_default();
+    edit(&mut profile);
+
+    let name = match &current {
+        Some(user) => user.name.as_str(),
+        None => msg
+            .member
+            .as_ref()
+            .and_then(|member| member.nick.as_deref())
+            .unwrap_or(&msg.author.name),
+    };
+    database
+        .update_user(
+            scope,
+            &id,
+            name,
+            &profile.to_json(),
+            RevisionSource::Admin,
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+fn parse_field(field: &str) -> anyhow::Result<Field> {
+    field.parse().map_err(|_| {
+        anyhow::anyhow!(
+            "unknown field `{}`, the fields are {}",
+            field,
+            Field::ALL.iter().join(", ")
+        )
+    })
+}
+
+const REVIEW_LENGTH: usize = 10;
+
+/// Lists, approves and rejects profile changes waiting for review.
+async fn review(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    let database = database(ctx).await;
+    let id = msg.author.id.to_string();
+
+    let (approve, number) = match args {
+        [] => {
+            let proposals = database
+                .get_profile_proposals((!is_admin(msg)).then_some(id.as_str()))
+                .await?;
+            if proposals.is_empty() {
+                return Ok("No profile changes are waiting for review.".to_string());
+            }
+            let mut reply = format!("{} profile changes are waiting:", proposals.len());
+            for proposal in proposals.iter().take(REVIEW_LENGTH) {
+                let changes = parse_changes(&proposal.changes)
+                    .iter()
+                    .map(|(field, value)| match value.as_str() {
+                        "" => format!("remove {}", field),
+                        value => format!("{}: {}", field, value),
+                    })
+                    .join("; ");
+                reply += &format!(
+                    "\n`#{}` {} in {}: {}",
+                    proposal.id,
+                    proposal.name,
+                    proposal.scope,
+                    shorten(&changes, HISTORY_PREVIEW_CHARS * 2)
+                );
+            }
+            return Ok(reply.replace('@', "@\u{200b}"));
+        }
+        ["approve", number] => (true, *number),
+        ["reject", number] => (false, *number),
+        _ => return Ok(HELP.to_string()),
+    };
+
+    let number = self::number(number)?;
+    let proposal = database
+        .get_profile_proposal(number)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("no profile change #{}", number))?;
+    if proposal.user_id != id && !is_admin(msg) {
+        anyhow::bail!("only the user or an admin can review a profile change");
+    }
+
+    if !approve {
+        database.delete_profile_proposal(proposal.id).await?;
+        return Ok(format!("Rejected profile change `#{}`.", proposal.id));
+    }
+    let changed = profile::approve(&database, &proposal).await?;
+    info!(
+        "{} approved profile change {} of user {}",
+        msg.author.name, proposal.id, proposal.user_id
+    );
+    if changed.is_empty() {
+        return Ok(format!(
+            "Approved profile change `#{}`, but there was nothing left to change.",
+            proposal.id
+        ));
+    }
+    Ok(format!(
+        "Approved profile change `#{}`: {} updated.",
+        proposal.id,
+        changed.iter().join(", ")
+    ))
+}
+
+async fn prune(ctx: &Context, msg: &Message) -> anyhow::Result<String> {
+    if !is_admin(msg) {
+        anyhow::bail!("only admins can prune messages");
+    }
+    let report = retention::prune_now(&database(ctx).await, &config::get()).await;
+    Ok(report.to_string())
+}
+
+const SEARCH_RESULTS: i64 = 5;
+const SEARCH_SNIPPET_CHARS: usize = 200;
+
+/// Searches the messages of this server, or of this DM.
+async fn search(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    let mut search = MessageSearch {
+        filter: MessageFilter {
+            channel: Some(msg.channel_id.0),
+            ..Default::default()
+        },
+        limit: SEARCH_RESULTS,
+        ..Default::default()
+    };
+    let mut words = Vec::new();
+    for arg in args {
+        match arg.split_once(':') {
+            Some(("from", user)) => search.sender_id = Some(mention_id(user)?.to_string()),
+            Some(("in", _)) if msg.guild_id.is_none() => {
+                anyhow::bail!("`in:` only works in a server, DMs search this DM")
+            }
+            Some(("in", channel)) => {
+                let channel = mention_id(channel)?;
+                if !can_view(ctx, msg, channel).await? {
+                    anyhow::bail!("you can't see that channel");
+                }
+                search.filter.channel = Some(channel)
+            }
+            Some(("after", date)) => search.filter.since = Some(parse_date(date)?),
+            Some(("before", date)) => search.filter.until = Some(parse_date(date)?),
+            _ => words.push(*arg),
+        }
+    }
+    search.text = words.join(" ");
+    if search.terms().is_empty() {
+        return Ok(HELP.to_string());
+    }
+
+    let messages = database(ctx).await.search_messages(&search).await?;
+    if messages.is_empty() {
+        return Ok("Nothing found.".to_string());
+    }
+    let clock = config::get().time.clock(msg.guild_id.map(|id| id.0));
+    let mut reply = String::new();
+    for message in messages {
+        // quoted messages must not ping anyone
+        let text = shorten(&message.message, SEARCH_SNIPPET_CHARS).replace('@', "@\u{200b}");
+        reply += &format!(
+            "\n**{}** {}: {}",
+            message.sender,
+            local_time(&clock, message.date_time).format("%Y-%m-%d %H:%M"),
+            text
+        );
+        if let Some(id) = &message.message_id {
+            let guild = msg
+                .guild_id
+                .map_or_else(|| "@me".to_string(), |id| id.to_string());
+            reply += &format!(
+                " <https://discord.com/channels/{}/{}/{}>",
+                guild, message.channel, id
+            );
+        }
+    }
+    Ok(reply.trim_start().to_string())
+}
+
+/// Whether the author of the message can read the history of a channel in its server.
+async fn can_view(ctx: &Context, msg: &Message, channel_id: u64) -> anyhow::Result<bool> {
+    let Some(guild_id) = msg.guild_id else {
+        return Ok(channel_id == msg.channel_id.0);
+    };
+    let channel = match ChannelId(channel_id).to_channel(ctx).await? {
+        Channel::Guild(channel) if channel.guild_id == guild_id => channel,
+        _ => return Ok(false),
+    };
+    let guild = guild_id.to_partial_guild(ctx).await?;
+    let member = guild_id.member(ctx, msg.author.id).await?;
+    let permissions = guild.user_permissions_in(&channel, &member)?;
+    Ok(permissions.view_channel() && permissions.read_message_history())
+}
+
+const HISTORY_LENGTH: i64 = 10;
+const HISTORY_PREVIEW_CHARS: usize = 80;
+/// Leaves room for the rest of a reply within Discord's 2000 characters.
+const REVISION_TEXT_CHARS: usize = 800;
+
+/// Lists the latest revisions of a summary or profile.
+async fn history(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    if !is_admin(msg) {
+        anyhow::bail!("only admins can see revisions");
+    }
+    let database = database(ctx).await;
+
+    let revisions = match args {
+        ["summary"] | ["summary", _] => {
+            let channel = match args.get(1) {
+                Some(channel) => mention_id(channel)?,
+                None => msg.channel_id.0,
+            };
+            database
+                .get_summary_history(channel, HISTORY_LENGTH)
+                .await?
+                .into_iter()
+                .map(|r| (r.id, r.created_at, r.source, r.summary))
+                .collect::<Vec<_>>()
+        }
+        ["profile", user] | ["profile", user, _] => {
+            let scope = match args.get(2) {
+                Some(scope) => scope
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("unknown profile scope `{}`", scope))?,
+                None => ProfileScope::of(msg.guild_id.map(|id| id.0)),
+            };
+            let user = mention_id(user)?.to_string();
+            database
+                .get_user_history(scope, &user, HISTORY_LENGTH)
+                .await?
+                .into_iter()
+                .map(|r| {
+                    (
+                        r.id,
+                        r.created_at,
+                        r.source,
+                        Profile::parse(&r.info).render(),
+                    )
+                })
+                .collect()
+        }
+        _ => return Ok(HELP.to_string()),
+    };
+
+    if revisions.is_empty() {
+        return Ok("No revisions yet.".to_string());
+    }
+    let mut reply = String::new();
+    for (id, created_at, source, text) in revisions {
+        reply += &format!(
+            "\n`#{}` {} {}: {}",
+            id,
+            created_at.format("%Y-%m-%d %H:%M"),
+            source,
+            shorten(&text, HISTORY_PREVIEW_CHARS).replace('@', "@\u{200b}")
+        );
+    }
+    Ok(reply.trim_start().to_string())
+}
+
+/// Shows one revision with the model response it was parsed from.
+async fn revision(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    if !is_admin(msg) {
+        anyhow::bail!("only admins can see revisions");
+    }
+    let database = database(ctx).await;
+
+    let (title, source, created_at, text, response) = match args {
+        ["summary", id] => {
+            let id = number(id)?;
+            let revision = database
+                .get_summary_revision(id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("no summary revision #{}", id))?;
+            (
+                format!("Summary of <#{}>", revision.channel),
+                revision.source,
+                revision.created_at,
+                revision.summary,
+                revision.model_response,
+            )
+        }
+        ["profile", id] => {
+            let id = number(id)?;
+            let revision = database
+                .get_user_revision(id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("no profile revision #{}", id))?;
+            (
+                format!(
+                    "Profile of {} ({}) in {}",
+                    revision.name, revision.user_id, revision.scope
+                ),
+                revision.source,
+                revision.created_at,
+                Profile::parse(&revision.info).render(),
+                revision.model_response,
+            )
+        }
+        _ => return Ok(HELP.to_string()),
+    };
+
+    let mut reply = format!(
+        "{}, {} {}:\n{}",
+        title,
+        source,
+        created_at.format("%Y-%m-%d %H:%M"),
+        shorten(&text, REVISION_TEXT_CHARS)
+    );
+    if let Some(response) = response {
+        reply += &format!(
+            "\nModel response:\n```\n{}\n```",
+            shorten(&response, REVISION_TEXT_CHARS)
+        );
+    }
+    Ok(reply.replace('@', "@\u{200b}"))
+}
+
+/// Compares two revisions of the same summary or profile word by word.
+async fn diff(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    if !is_admin(msg) {
+        anyhow::bail!("only admins can see revisions");
+    }
+    let database = database(ctx).await;
+
+    let ((old_id, old), (new_id, new)) = match args {
+        ["summary", old] | ["summary", old, _] => {
+            let old = number(old)?;
+            let old = database
+                .get_summary_revision(old)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("no summary revision #{}", old))?;
+            let new = match args.get(2) {
+                Some(new) => {
+                    let new = number(new)?;
+                    database
+                        .get_summary_revision(new)
+                        .await?
+                        .filter(|r| r.channel == old.channel)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("no summary revision #{} of the same channel", new)
+                        })?
+                }
+                None => {
+                    let channel = old.channel.parse()?;
+                    let mut latest = database.get_summary_history(channel, 1).await?;
+                    latest.pop().unwrap_or_else(|| old.clone())
+                }
+            };
+            ((old.id, old.summary), (new.id, new.summary))
+        }
+        ["profile", old] | ["profile", old, _] => {
+            let old = number(old)?;
+            let old = database
+                .get_user_revision(old)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("no profile revision #{}", old))?;
+            let new = match args.get(2) {
+                Some(new) => {
+                    let new = number(new)?;
+                    database
+                        .get_user_revision(new)
+                        .await?
+                        .filter(|r| r.user_id == old.user_id && r.scope == old.scope)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("no profile revision #{} of the same profile", new)
+                        })?
+                }
+                None => {
+                    let scope = old
+                        .scope
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("unknown profile scope `{}`", old.scope))?;
+                    let mut latest = database.get_user_history(scope, &old.user_id, 1).await?;
+                    latest.pop().unwrap_or_else(|| old.clone())
+                }
+            };
+            (
+                (old.id, Profile::parse(&old.info).render()),
+                (new.id, Profile::parse(&new.info).render()),
+            )
+        }
+        _ => return Ok(HELP.to_string()),
+    };
+
+    if old == new {
+        return Ok(format!("No changes from `#{}` to `#{}`.", old_id, new_id));
+    }
+    Ok(format!(
+        "Changes from `#{}` to `#{}`:\n```\n{}\n```",
+        old_id,
+        new_id,
+        shorten(&word_diff(&old, &new), 2 * REVISION_TEXT_CHARS)
+    ))
+}
+
+/// Makes an earlier revision of a summary or profile current again.
+async fn rollback(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    if !is_admin(msg) {
+        anyhow::bail!("only admins can roll back revisions");
+    }
+    let database = database(ctx).await;
+
+    match args {
+        ["summary", id] => {
+            let id = number(id)?;
+            let revision = database
+                .rollback_summary(id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("no summary revision #{}", id))?;
+            info!(
+                "{} rolled back the summary of channel {} to revision {}",
+                msg.author.name, revision.channel, id
+            );
+            Ok(format!(
+                "The summary of <#{}> is revision `#{}` again.",
+                revision.channel, id
+            ))
+        }
+        ["profile", id] => {
+            let id = number(id)?;
+            let revision = database
+                .rollback_user(id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("no profile revision #{}", id))?;
+            info!(
+                "{} rolled back the profile of user {} in {} to revision {}",
+                msg.author.name, revision.user_id, revision.scope, id
+            );
+            Ok(format!(
+                "The profile of {} in {} is revision `#{}` again.",
+                revision.name, revision.scope, id
+            ))
+        }
+        _ => Ok(HELP.to_string()),
+    }
+}
+
+/// Leaves room for the header of a template preview.
+const PREVIEW_CHARS: usize = 1800;
+
+async fn templates(ctx: &Context, msg: &Message, args: &[&str]) -> anyhow::Result<String> {
+    if !is_admin(msg) {
+        anyhow::bail!("only admins can see prompt templates");
+    }
+    match args {
+        [] => {
+            let templates = templates::get();
+            let overrides = templates.overrides();
+            if overrides.is_empty() {
+                return Ok("All prompt templates are built-in.".to_string());
+            }
+            let mut reply = "Prompt templates loaded from files:".to_string();
+            for (name, source) in overrides {
+                reply += &format!("\n- `{}` from `{}`", name, source);
+            }
+            Ok(shorten(&reply, PREVIEW_CHARS))
+        }
+        ["reload"] => {
+            let errors = templates::reload();
+            let mut reply = format!(
+                "Reloaded prompt templates, {} loaded from files.",
+                templates::get().overrides().len()
+            );
+            for error in errors {
+                reply += &format!("\nFailed: {}", error);
+            }
+            Ok(shorten(&reply, PREVIEW_CHARS))
+        }
+        ["preview", name] | ["preview", name, _] => {
+            if !templates::is_chat_template(name) {
+                anyhow::bail!("`{}` is not a chat prompt template", name);
+            }
+            let channel = match args.get(2) {
+                Some(channel) => mention_id(channel)?,
+                None => msg.channel_id.0,
+            };
+            let config = config::get();
+            let mut info = prompt_context(
+                &database(ctx).await,
+                channel,
+                config.chat.min_messages,
+                config.chat.summary_chars,
+                &config.time,
+            )
+            .await?;
+            // a channel without messages yet still gets its server's templates
+            info.context.guild = info.context.guild.or(msg.guild_id.map(|id| id.0));
+
+            let templates = templates::get();
+            let location = info.context.location();
+            let prompt = templates.render(name, location, &info.context)?;
+            // the prompt must not ping anyone or close the code block
+            let prompt = shorten(&prompt, PREVIEW_CHARS)
+                .replace('@', "@\u{200b}")
+                .replace("```", "`\u{200b}``");
+            Ok(format!(
+                "`{}` from `{}`:\n```\n{}\n```",
+                name,
+                templates.source(name, location).unwrap_or_default(),
+                prompt
+            ))
+        }
+        _ => Ok(HELP.to_string()),
+    }
+}
+
+/// A revision or proposal number, with or without the `#`.
+fn number(arg: &str) -> anyhow::Result<i64> {
+    arg.trim_start_matches('#')
+        .parse()
+        .map_err(|_| anyhow::anyhow!("`{}` is not a number", arg))
+}
+
+/// The first `chars` characters of the text, with an ellipsis if anything was cut.
+fn shorten(text: &str, chars: usize) -> String {
+    let mut short = text.chars().take(chars).collect::<String>();
+    if short.len() < text.len() {
+        short += "…";
+    }
+    short
+}
+
+/// The id in a user or channel mention, or a plain id.
+fn mention_id(mention: &str) -> anyhow::Result<u64> {
+    let id = mention
+        .trim_start_matches(['<', '@', '!', '#'])
+        .trim_end_matches('>');
+    id.parse()
+        .map_err(|_| anyhow::anyhow!("`{}` is not a user, channel or id", mention))
+}
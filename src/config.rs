@@ -0,0 +1,722 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use chrono::Locale;
+use chrono_tz::Tz;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::access::{Access, Scope};
+use crate::prompts::PromptLayout;
+use crate::retention::RetentionAction;
+use crate::split::ReplyFile;
+
+const DEFAULT_CONFIG_PATH: &str = "kasumi.toml";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {0:?}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse config file")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub discord: DiscordConfig,
+    pub access: AccessConfig,
+    pub openai: OpenAiConfig,
+    pub database: DatabaseConfig,
+    pub logs: LogsConfig,
+    pub templates: TemplatesConfig,
+    pub time: TimeConfig,
+    pub chat: ChatConfig,
+    pub direct_messages: DirectMessagesConfig,
+    pub summarizer: SummarizerConfig,
+    pub retention: RetentionConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordConfig {
+    /// Overridden by `DISCORD_TOKEN`.
+    pub token: String,
+    /// Messages starting with this are treated as commands.
+    pub command_prefix: String,
+    /// Users allowed to run admin commands.
+    pub admins: Vec<u64>,
+    /// How long names of members, channels and roles are used without asking Discord.
+    pub name_cache_secs: u64,
+    /// Receive member joins, leaves and nickname changes.
+    /// Needs the privileged Server Members intent of the bot application.
+    pub member_events: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Used when no list matches a guild channel.
+    pub default: Access,
+    pub direct_messages: Access,
+    pub active: AccessList,
+    pub read_only: AccessList,
+    pub deny: AccessList,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessList {
+    pub guilds: Vec<u64>,
+    pub categories: Vec<u64>,
+    pub channels: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAiConfig {
+    /// Overridden by `OPENAI_KEY`.
+    pub key: String,
+    pub model: String,
+    pub temperature: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Overridden by `DATABASE_URL`.
+    pub url: String,
+    pub max_connections: u32,
+    /// How long to wait for a locked database or a free connection.
+    pub busy_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogsConfig {
+    pub directory: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplatesConfig {
+    /// Prompt templates replacing the built-in ones, see `templates.rs`.
+    pub directory: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeConfig {
+    /// IANA timezone of dates and times in prompts, e.g. `Europe/Moscow`.
+    pub timezone: String,
+    /// Locale of month and weekday names, e.g. `ru_RU`.
+    pub locale: String,
+    /// Per guild overrides.
+    pub guilds: Vec<GuildTime>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuildTime {
+    pub guild: u64,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+}
+
+/// How one guild, or DMs, tell the time.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    pub timezone: Tz,
+    pub locale: Locale,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    /// How long to wait for a follow-up message before replying.
+    pub debounce_secs: u64,
+    /// Messages always included in the prompt, even if already summarized.
+    pub min_messages: i64,
+    /// Replies using more tokens than this trigger a summarization.
+    pub max_tokens: usize,
+    /// Old messages Kasumi can look up when someone refers to them, 0 disables it.
+    pub recall_results: i64,
+    /// How much of the channel's history summaries goes into a prompt, in characters.
+    pub summary_chars: usize,
+    /// How the chat log is laid out in prompts.
+    pub layout: PromptLayout,
+    /// Replies longer than this are sent as a file instead of several messages, 0 never does.
+    pub attachment_chars: usize,
+    /// The file type of replies sent as files.
+    pub attachment_file: ReplyFile,
+    /// Kasumi stops typing after this long even if no reply came.
+    pub typing_timeout_secs: u64,
+    /// Per channel overrides.
+    pub channels: Vec<ChannelChat>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelChat {
+    pub channel: u64,
+    pub layout: Option<PromptLayout>,
+}
+
+/// Private conversations, always answered with the `chat_dm` template.
+/// Whether Kasumi takes DMs at all is up to `access.direct_messages`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirectMessagesConfig {
+    /// Replies to one user within `window_secs`, 0 doesn't limit them.
+    pub max_replies: usize,
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SummarizerConfig {
+    /// How often to look for channels to summarize.
+    pub interval_secs: u64,
+    /// New messages that make a channel due for a summary.
+    pub max_new_messages: usize,
+    /// Estimated tokens of new messages that make a channel due for a summary.
+    pub max_new_tokens: usize,
+    /// A channel with new messages is summarized once it was quiet for this long.
+    pub idle_secs: u64,
+    /// Channels summarized at the same time.
+    pub concurrency: usize,
+    pub temperature: f32,
+    /// Profile changes wait for the user or an admin to approve them.
+    pub review_profiles: bool,
+    /// Weekly summaries kept before they are folded into the long-term summary.
+    pub weekly_summaries: usize,
+    /// Profiles updated per summary, of the participants who wrote the most.
+    pub max_profiles: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub interval_secs: u64,
+    /// What happens to old messages. Only already summarized messages are ever pruned.
+    pub action: RetentionAction,
+    /// Messages newer than this stay in the messages table.
+    pub keep_days: Option<u64>,
+    /// The last messages of a channel that stay in the messages table.
+    pub keep_messages: Option<u64>,
+    /// Per channel overrides.
+    pub channels: Vec<ChannelRetention>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelRetention {
+    pub channel: u64,
+    pub action: Option<RetentionAction>,
+    pub keep_days: Option<u64>,
+    pub keep_messages: Option<u64>,
+}
+
+/// Retention settings for one channel.
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    pub action: RetentionAction,
+    pub keep_days: Option<u64>,
+    pub keep_messages: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long pending replies may take to finish on shutdown.
+    pub grace_period_secs: u64,
+}
+
+impl Default for DiscordConfig {
+    fn default() -> Self {
+        Self {
+            token: String::new(),
+            command_prefix: "!kasumi".to_string(),
+            admins: Vec::new(),
+            name_cache_secs: 60 * 60,
+            member_events: false,
+        }
+    }
+}
+
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        Self {
+            key: String::new(),
+            model: "gpt-3.5-turbo".to_string(),
+            temperature: 0.4,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: "sqlite:data.db".to_string(),
+            max_connections: 8,
+            busy_timeout_secs: 5,
+        }
+    }
+}
+
+impl Default for LogsConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("logs"),
+        }
+    }
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("templates"),
+        }
+    }
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        Self {
+            timezone: "UTC".to_string(),
+            locale: "en_US".to_string(),
+            guilds: Vec::new(),
+        }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            debounce_secs: 5,
+            min_messages: 6,
+            max_tokens: 3000,
+            recall_results: 5,
+            summary_chars: 4000,
+            layout: PromptLayout::Transcript,
+            attachment_chars: 0,
+            attachment_file: ReplyFile::Md,
+            typing_timeout_secs: 120,
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl Default for SummarizerConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 60,
+            max_new_messages: 50,
+            max_new_tokens: 1500,
+            idle_secs: 10 * 60,
+            concurrency: 2,
+            temperature: 0.4,
+            review_profiles: false,
+            weekly_summaries: 4,
+            max_profiles: 5,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 60 * 60,
+            action: RetentionAction::Keep,
+            keep_days: None,
+            keep_messages: None,
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl Default for DirectMessagesConfig {
+    fn default() -> Self {
+        Self {
+            max_replies: 20,
+            window_secs: 60 * 60,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            grace_period_secs: 30,
+        }
+    }
+}
+
+impl AccessConfig {
+    /// Deny wins over read only, read only wins over active.
+    pub fn lookup(&self, scope: Scope, target: u64) -> Option<Access> {
+        [
+            (&self.deny, Access::Deny),
+            (&self.read_only, Access::ReadOnly),
+            (&self.active, Access::Active),
+        ]
+        .into_iter()
+        .find(|(list, _)| list.contains(scope, target))
+        .map(|(_, access)| access)
+    }
+
+    pub fn uses_categories(&self) -> bool {
+        [&self.deny, &self.read_only, &self.active]
+            .iter()
+            .any(|list| !list.categories.is_empty())
+    }
+}
+
+impl AccessList {
+    fn contains(&self, scope: Scope, target: u64) -> bool {
+        match scope {
+            Scope::Guild => self.guilds.contains(&target),
+            Scope::Category => self.categories.contains(&target),
+            Scope::Channel => self.channels.contains(&target),
+            Scope::Direct => false,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn busy_timeout(&self) -> Duration {
+        Duration::from_secs(self.busy_timeout_secs)
+    }
+}
+
+impl DiscordConfig {
+    pub fn name_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.name_cache_secs)
+    }
+}
+
+impl ChatConfig {
+    pub fn debounce(&self) -> Duration {
+        Duration::from_secs(self.debounce_secs)
+    }
+
+    pub fn typing_timeout(&self) -> Duration {
+        Duration::from_secs(self.typing_timeout_secs)
+    }
+}
+
+impl DirectMessagesConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+impl SummarizerConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle_secs)
+    }
+}
+
+impl RetentionConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    /// The channel override, with unset values taken from the defaults.
+    pub fn policy(&self, channel: u64) -> RetentionPolicy {
+        let default = RetentionPolicy {
+            action: self.action,
+            keep_days: self.keep_days,
+            keep_messages: self.keep_messages,
+        };
+        match self.channels.iter().find(|c| c.channel == channel) {
+            Some(c) => RetentionPolicy {
+                action: c.action.unwrap_or(default.action),
+                keep_days: c.keep_days.or(default.keep_days),
+                keep_messages: c.keep_messages.or(default.keep_messages),
+            },
+            None => default,
+        }
+    }
+}
+
+impl ChatConfig {
+    pub fn layout(&self, channel: u64) -> PromptLayout {
+        self.channels
+            .iter()
+            .find(|c| c.channel == channel)
+            .and_then(|c| c.layout)
+            .unwrap_or(self.layout)
+    }
+}
+
+impl TimeConfig {
+    /// The clock of a guild, or of DMs without one. Names are checked when the config loads.
+    pub fn clock(&self, guild: Option<u64>) -> Clock {
+        let guild = guild.and_then(|id| self.guilds.iter().find(|g| g.guild == id));
+        let timezone = guild
+            .and_then(|g| g.timezone.as_deref())
+            .unwrap_or(&self.timezone);
+        let locale = guild
+            .and_then(|g| g.locale.as_deref())
+            .unwrap_or(&self.locale);
+        Clock {
+            timezone: timezone.parse().unwrap_or(Tz::UTC),
+            locale: Locale::try_from(locale).unwrap_or(Locale::en_US),
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let timezones = self.guilds.iter().filter_map(|g| g.timezone.as_deref());
+        for timezone in timezones.chain([self.timezone.as_str()]) {
+            timezone
+                .parse::<Tz>()
+                .map_err(|_| format!("unknown time zone `{}`", timezone))?;
+        }
+        let locales = self.guilds.iter().filter_map(|g| g.locale.as_deref());
+        for locale in locales.chain([self.locale.as_str()]) {
+            Locale::try_from(locale).map_err(|_| format!("unknown locale `{}`", locale))?;
+        }
+        Ok(())
+    }
+}
+
+impl ShutdownConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_secs)
+    }
+}
+
+impl Config {
+    /// Reads the config file and applies environment overrides.
+    /// A missing file is only an error if the path was given explicitly.
+    pub fn load(path: &Path, required: bool) -> Result<Self, ConfigError> {
+        let mut config: Config = match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)?,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(ConfigError::Read(path.to_path_buf(), e)),
+        };
+
+        if let Ok(token) = env::var("DISCORD_TOKEN") {
+            config.discord.token = token;
+        }
+        if let Ok(key) = env::var("OPENAI_KEY") {
+            config.openai.key = key;
+        }
+        if let Ok(url) = env::var("DATABASE_URL") {
+            config.database.url = url;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        fn invalid(message: &str) -> Result<(), ConfigError> {
+            Err(ConfigError::Invalid(message.to_string()))
+        }
+
+        if self.discord.command_prefix.trim().is_empty() {
+            return invalid("discord.command_prefix must not be empty");
+        }
+        if self.openai.model.is_empty() {
+            return invalid("openai.model must not be empty");
+        }
+        if self.database.url.is_empty() {
+            return invalid("database.url or DATABASE_URL must be set");
+        }
+        if !(0.0..=2.0).contains(&self.openai.temperature)
+            || !(0.0..=2.0).contains(&self.summarizer.temperature)
+        {
+            return invalid("temperatures must be between 0 and 2");
+        }
+        if self.database.max_connections == 0 {
+            return invalid("database.max_connections must be positive");
+        }
+        if self.chat.min_messages < 0 {
+            return invalid("chat.min_messages must not be negative");
+        }
+        if self.chat.recall_results < 0 {
+            return invalid("chat.recall_results must not be negative");
+        }
+        if self.chat.max_tokens == 0 {
+            return invalid("chat.max_tokens must be positive");
+        }
+        if self.chat.summary_chars == 0 {
+            return invalid("chat.summary_chars must be positive");
+        }
+        if self.chat.typing_timeout_secs == 0 {
+            return invalid("chat.typing_timeout_secs must be positive");
+        }
+        if self.direct_messages.max_replies > 0 && self.direct_messages.window_secs == 0 {
+            return invalid("direct_messages.window_secs must be positive");
+        }
+        if let Err(e) = self.time.check() {
+            return invalid(&format!("time: {}", e));
+        }
+        if self.summarizer.interval_secs == 0 {
+            return invalid("summarizer.interval_secs must be positive");
+        }
+        if self.summarizer.max_new_messages == 0 || self.summarizer.max_new_tokens == 0 {
+            return invalid("summarizer.max_new_messages and max_new_tokens must be positive");
+        }
+        if self.summarizer.concurrency == 0 {
+            return invalid("summarizer.concurrency must be positive");
+        }
+        if self.retention.interval_secs == 0 {
+            return invalid("retention.interval_secs must be positive");
+        }
+        Ok(())
+    }
+
+    /// Checks the secrets only the bot itself needs, not the export and import commands.
+    pub fn check_secrets(&self) -> Result<(), ConfigError> {
+        if self.discord.token.is_empty() {
+            return Err(ConfigError::Invalid(
+                "discord.token or DISCORD_TOKEN must be set".to_string(),
+            ));
+        }
+        if self.openai.key.is_empty() {
+            return Err(ConfigError::Invalid(
+                "openai.key or OPENAI_KEY must be set".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Keeps the settings that can't change while the bot is running.
+    fn keep_static(&mut self, old: &Config) {
+        if self.discord.token != old.discord.token {
+            warn!("discord.token changed, restart to apply");
+            self.discord.token = old.discord.token.clone();
+        }
+        if self.discord.name_cache_secs != old.discord.name_cache_secs
+            || self.discord.member_events != old.discord.member_events
+        {
+            warn!("discord.name_cache_secs and member_events changed, restart to apply");
+            self.discord.name_cache_secs = old.discord.name_cache_secs;
+            self.discord.member_events = old.discord.member_events;
+        }
+        if self.openai.key != old.openai.key {
+            warn!("openai.key changed, restart to apply");
+            self.openai.key = old.openai.key.clone();
+        }
+        if self.database.url != old.database.url
+            || self.database.max_connections != old.database.max_connections
+            || self.database.busy_timeout_secs != old.database.busy_timeout_secs
+        {
+            warn!("database settings changed, restart to apply");
+            self.database = old.database.clone();
+        }
+        if self.logs.directory != old.logs.directory {
+            warn!("logs.directory changed, restart to apply");
+            self.logs.directory = old.logs.directory.clone();
+        }
+    }
+}
+
+struct State {
+    path: PathBuf,
+    required: bool,
+    config: Arc<Config>,
+}
+
+static STATE: Lazy<RwLock<Option<State>>> = Lazy::new(|| RwLock::new(None));
+
+/// Loads the config from `KASUMI_CONFIG` (or `kasumi.toml`).
+pub fn init() -> Result<Arc<Config>, ConfigError> {
+    let (path, required) = match env::var("KASUMI_CONFIG") {
+        Ok(path) => (PathBuf::from(path), true),
+        Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+    };
+    let config = Arc::new(Config::load(&path, required)?);
+    *STATE.write().unwrap() = Some(State {
+        path,
+        required,
+        config: config.clone(),
+    });
+    Ok(config)
+}
+
+/// Returns the current config snapshot.
+pub fn get() -> Arc<Config> {
+    STATE
+        .read()
+        .unwrap()
+        .as_ref()
+        .expect("Config is not initialized")
+        .config
+        .clone()
+}
+
+/// Reloads the config file. On error the current config is kept.
+pub fn reload() -> Result<(), ConfigError> {
+    let (path, required, old) = {
+        let state = STATE.read().unwrap();
+        let state = state.as_ref().expect("Config is not initialized");
+        (state.path.clone(), state.required, state.config.clone())
+    };
+
+    let mut config = Config::load(&path, required)?;
+    config.keep_static(&old);
+
+    if let Some(state) = STATE.write().unwrap().as_mut() {
+        state.config = Arc::new(config);
+    }
+    info!("Reloaded config from {:?}", path);
+    Ok(())
+}
+
+fn modified() -> Option<SystemTime> {
+    let path = STATE.read().unwrap().as_ref()?.path.clone();
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reloads the config on SIGHUP or when the file changes.
+pub async fn watch() {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to install SIGHUP handler");
+
+    let mut last_modified = modified();
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        #[cfg(unix)]
+        let forced = tokio::select! {
+            _ = interval.tick() => false,
+            _ = hangup.recv() => {
+                info!("Received SIGHUP");
+                true
+            }
+        };
+        #[cfg(not(unix))]
+        let forced = {
+            interval.tick().await;
+            false
+        };
+
+        let current = modified();
+        if !forced && current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        match reload() {
+            Ok(()) => {
+                crate::templates::reload();
+            }
+            Err(e) => warn!("Failed to reload config: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,752 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+
+use super::{
+    compress_messages, decompress_messages, DbAccessRule, DbMessage, DbPeriodSummary,
+    DbProfileProposal, DbSeenName, DbSummary, DbSummaryRevision, DbUser, DbUserName,
+    DbUserRevision, DbUserSettings, MessageFilter, MessageSearch, ProfileScope, RevisionSource,
+    Storage, SummaryLevel, KASUMI_ID,
+};
+
+#[derive(Default)]
+struct State {
+    messages: Vec<DbMessage>,
+    /// Keyed by id and scope.
+    users: BTreeMap<(String, String), DbUser>,
+    /// Keyed by user id and name, with the times it was first and last seen.
+    user_names: BTreeMap<(String, String), (NaiveDateTime, NaiveDateTime)>,
+    share_profile: HashMap<String, bool>,
+    summaries: BTreeMap<String, DbSummary>,
+    /// In the order they were made, ids start at 1.
+    summary_revisions: Vec<DbSummaryRevision>,
+    user_revisions: Vec<DbUserRevision>,
+    profile_proposals: Vec<DbProfileProposal>,
+    next_proposal: i64,
+    /// With whether they are rolled up.
+    period_summaries: Vec<(DbPeriodSummary, bool)>,
+    next_period_summary: i64,
+    /// Compressed batches, keyed by channel.
+    archive: BTreeMap<String, Vec<Vec<u8>>>,
+    /// Keyed by scope and target.
+    access_rules: BTreeMap<(String, String), String>,
+}
+
+/// Storage that lives only as long as the process, for tests.
+#[derive(Default)]
+pub struct MemoryDatabase {
+    state: Mutex<State>,
+}
+
+impl MemoryDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl State {
+    fn channel_messages(&self, channel: u64) -> Vec<DbMessage> {
+        let channel = channel.to_string();
+        let mut messages = self
+            .messages
+            .iter()
+            .filter(|m| m.channel == channel)
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|m| m.date_time);
+        messages
+    }
+
+    fn set_summary(
+        &mut self,
+        summary: DbSummary,
+        source: RevisionSource,
+        model_response: Option<&str>,
+        created_at: NaiveDateTime,
+    ) {
+        self.summary_revisions.push(DbSummaryRevision {
+            id: self.summary_revisions.len() as i64 + 1,
+            channel: summary.channel.clone(),
+            summary: summary.summary.clone(),
+            source: source.to_string(),
+            model_response: model_response.map(str::to_string),
+            created_at,
+        });
+        self.summaries.insert(summary.channel.clone(), summary);
+    }
+
+    fn add_period_summary(&mut self, summary: &DbPeriodSummary) -> i64 {
+        self.next_period_summary += 1;
+        let id = self.next_period_summary;
+        self.period_summaries.push((
+            DbPeriodSummary {
+                id,
+                ..summary.clone()
+            },
+            false,
+        ));
+        id
+    }
+
+    fn set_user(&mut self, user: DbUser, source: RevisionSource, model_response: Option<&str>) {
+        self.user_revisions.push(DbUserRevision {
+            id: self.user_revisions.len() as i64 + 1,
+            user_id: user.id.clone(),
+            scope: user.scope.clone(),
+            name: user.name.clone(),
+            info: user.info.clone(),
+            source: source.to_string(),
+            model_response: model_response.map(str::to_string),
+            created_at: user.last_update,
+        });
+        self.users
+            .insert((user.id.clone(), user.scope.clone()), user);
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryDatabase {
+    async fn get_messages_by_date(
+        &self,
+        channel: u64,
+        after: NaiveDateTime,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let mut messages = state.channel_messages(channel);
+        messages.retain(|m| m.date_time > after);
+        Ok(messages)
+    }
+
+    async fn get_messages_by_count(
+        &self,
+        channel: u64,
+        count: i64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let mut messages = state.channel_messages(channel);
+        let skip = messages.len().saturating_sub(count.max(0) as usize);
+        Ok(messages.split_off(skip))
+    }
+
+    async fn add_message(&self, message: &DbMessage) -> Result<(), sqlx::error::Error> {
+        self.state.lock().unwrap().messages.push(message.clone());
+        Ok(())
+    }
+
+    async fn search_messages(
+        &self,
+        search: &MessageSearch,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let terms = search.terms();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let state = self.state.lock().unwrap();
+        let mut messages = state
+            .messages
+            .iter()
+            .filter(|m| search.filter.matches(m))
+            .filter(|m| {
+                search
+                    .sender_id
+                    .as_ref()
+                    .is_none_or(|id| *id == m.sender_id)
+            })
+            .filter(|m| {
+                let words = m
+                    .message
+                    .split(|c: char| !c.is_alphanumeric())
+                    .map(|word| word.to_lowercase())
+                    .collect::<Vec<_>>();
+                terms
+                    .iter()
+                    .all(|term| words.iter().any(|word| word.starts_with(term)))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|m| std::cmp::Reverse(m.date_time));
+        messages.truncate(search.limit.max(0) as usize);
+        Ok(messages)
+    }
+
+    async fn get_users(
+        &self,
+        scope: ProfileScope,
+        ids: &[String],
+    ) -> Result<Vec<DbUser>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let scope = scope.to_string();
+        let global = ProfileScope::Global.to_string();
+        Ok(state
+            .users
+            .values()
+            .filter(|u| ids.contains(&u.id))
+            .filter(|u| {
+                u.scope == scope
+                    || (u.scope == global
+                        && (u.id == KASUMI_ID
+                            || state.share_profile.get(&u.id).copied().unwrap_or(false)))
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn update_user(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        name: &str,
+        info: &str,
+        source: RevisionSource,
+        model_response: Option<&str>,
+    ) -> Result<(), sqlx::error::Error> {
+        let user = DbUser {
+            id: id.to_string(),
+            scope: scope.to_string(),
+            name: name.to_string(),
+            info: info.to_string(),
+            last_update: Utc::now().naive_utc(),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .set_user(user, source, model_response);
+        Ok(())
+    }
+
+    async fn shares_profile(&self, id: &str) -> Result<bool, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.share_profile.get(id).copied().unwrap_or(false))
+    }
+
+    async fn set_share_profile(&self, id: &str, share: bool) -> Result<(), sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.share_profile.insert(id.to_string(), share);
+        Ok(())
+    }
+
+    async fn touch_user(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        username: &str,
+        display_name: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let legacy_id = format!("legacy:{}", username);
+        let scope_key = scope.to_string();
+
+        let now = Utc::now().naive_utc();
+        state
+            .user_names
+            .entry((id.to_string(), display_name.to_string()))
+            .and_modify(|(_, last_seen)| *last_seen = now)
+            .or_insert((now, now));
+
+        let legacy = state
+            .users
+            .keys()
+            .filter(|(user, _)| *user == legacy_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in legacy {
+            let adopted = (id.to_string(), key.1.clone());
+            if !state.users.contains_key(&adopted) {
+                let mut user = state.users.remove(&key).unwrap();
+                user.id = id.to_string();
+                state.users.insert(adopted, user);
+                for revision in state.user_revisions.iter_mut() {
+                    if revision.user_id == legacy_id && revision.scope == key.1 {
+                        revision.user_id = id.to_string();
+                    }
+                }
+            }
+        }
+
+        for message in state.messages.iter_mut() {
+            if message.sender_id == legacy_id {
+                message.sender_id = id.to_string();
+            }
+        }
+
+        if let ProfileScope::Guild(_) = scope {
+            let legacy = (id.to_string(), ProfileScope::Legacy.to_string());
+            let scoped = (id.to_string(), scope_key.clone());
+            if !state.users.contains_key(&scoped) {
+                if let Some(mut user) = state.users.remove(&legacy) {
+                    user.scope = scope_key.clone();
+                    state.users.insert(scoped, user);
+                    for revision in state.user_revisions.iter_mut() {
+                        if revision.user_id == id && revision.scope == legacy.1 {
+                            revision.scope = scope_key.clone();
+                        }
+                    }
+                }
+            }
+        }
+
+        let global = ProfileScope::Global.to_string();
+        for user in state.users.values_mut() {
+            if user.id == id && (user.scope == scope_key || user.scope == global) {
+                user.name = display_name.to_string();
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_user_names(&self, ids: &[String]) -> Result<Vec<DbUserName>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let mut names = state
+            .user_names
+            .iter()
+            .filter(|((user_id, _), _)| ids.contains(user_id))
+            .collect::<Vec<_>>();
+        names.sort_by_key(|(_, (_, last_seen))| std::cmp::Reverse(*last_seen));
+        Ok(names
+            .into_iter()
+            .map(|((user_id, name), _)| DbUserName {
+                user_id: user_id.clone(),
+                name: name.clone(),
+            })
+            .collect())
+    }
+
+    async fn get_user_history(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        limit: i64,
+    ) -> Result<Vec<DbUserRevision>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let scope = scope.to_string();
+        Ok(state
+            .user_revisions
+            .iter()
+            .rev()
+            .filter(|r| r.user_id == id && r.scope == scope)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_user_revision(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbUserRevision>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.user_revisions.iter().find(|r| r.id == id).cloned())
+    }
+
+    async fn rollback_user(&self, id: i64) -> Result<Option<DbUserRevision>, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let Some(revision) = state.user_revisions.iter().find(|r| r.id == id).cloned() else {
+            return Ok(None);
+        };
+        let user = DbUser {
+            id: revision.user_id.clone(),
+            scope: revision.scope.clone(),
+            name: revision.name.clone(),
+            info: revision.info.clone(),
+            last_update: Utc::now().naive_utc(),
+        };
+        state.set_user(user, RevisionSource::Admin, None);
+        Ok(Some(revision))
+    }
+
+    async fn add_profile_proposal(
+        &self,
+        proposal: &DbProfileProposal,
+    ) -> Result<i64, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.next_proposal += 1;
+        let id = state.next_proposal;
+        state.profile_proposals.push(DbProfileProposal {
+            id,
+            ..proposal.clone()
+        });
+        Ok(id)
+    }
+
+    async fn get_profile_proposals(
+        &self,
+        user_id: Option<&str>,
+    ) -> Result<Vec<DbProfileProposal>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .profile_proposals
+            .iter()
+            .filter(|p| user_id.is_none_or(|id| p.user_id == id))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_profile_proposal(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbProfileProposal>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.profile_proposals.iter().find(|p| p.id == id).cloned())
+    }
+
+    async fn delete_profile_proposal(&self, id: i64) -> Result<bool, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.profile_proposals.len();
+        state.profile_proposals.retain(|p| p.id != id);
+        Ok(state.profile_proposals.len() < count)
+    }
+
+    async fn get_summary(&self, channel: u64) -> Result<Option<DbSummary>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.summaries.get(&channel.to_string()).cloned())
+    }
+
+    async fn update_summary(
+        &self,
+        channel: u64,
+        summary: &str,
+        source: RevisionSource,
+        model_response: Option<&str>,
+        session_start: Option<NaiveDateTime>,
+    ) -> Result<(), sqlx::error::Error> {
+        let now = Utc::now().naive_utc();
+        let mut state = self.state.lock().unwrap();
+        if let Some(period_start) = session_start {
+            state.add_period_summary(&DbPeriodSummary {
+                id: 0,
+                channel: channel.to_string(),
+                level: SummaryLevel::Session.to_string(),
+                period_start,
+                period_end: now,
+                summary: summary.to_string(),
+                created_at: now,
+            });
+        }
+        let summary = DbSummary {
+            channel: channel.to_string(),
+            summary: summary.to_string(),
+            last_update: now,
+        };
+        state.set_summary(summary, source, model_response, now);
+        Ok(())
+    }
+
+    async fn get_period_summaries(
+        &self,
+        channel: u64,
+    ) -> Result<Vec<DbPeriodSummary>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let channel = channel.to_string();
+        let mut summaries = state
+            .period_summaries
+            .iter()
+            .filter(|(s, rolled_up)| s.channel == channel && !rolled_up)
+            .map(|(s, _)| s.clone())
+            .collect::<Vec<_>>();
+        summaries.sort_by_key(|s| (s.period_start, s.id));
+        Ok(summaries)
+    }
+
+    async fn roll_up_summaries(
+        &self,
+        parts: &[i64],
+        summary: &DbPeriodSummary,
+    ) -> Result<i64, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        for (s, rolled_up) in &mut state.period_summaries {
+            if parts.contains(&s.id) {
+                *rolled_up = true;
+            }
+        }
+        Ok(state.add_period_summary(summary))
+    }
+
+    async fn get_summary_history(
+        &self,
+        channel: u64,
+        limit: i64,
+    ) -> Result<Vec<DbSummaryRevision>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let channel = channel.to_string();
+        Ok(state
+            .summary_revisions
+            .iter()
+            .rev()
+            .filter(|r| r.channel == channel)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_summary_revision(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbSummaryRevision>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.summary_revisions.iter().find(|r| r.id == id).cloned())
+    }
+
+    async fn rollback_summary(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbSummaryRevision>, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let Some(revision) = state.summary_revisions.iter().find(|r| r.id == id).cloned() else {
+            return Ok(None);
+        };
+        let summary = DbSummary {
+            channel: revision.channel.clone(),
+            summary: revision.summary.clone(),
+            last_update: revision.created_at,
+        };
+        let session = SummaryLevel::Session.to_string();
+        state.period_summaries.retain(|(s, rolled_up)| {
+            s.channel != revision.channel
+                || s.level != session
+                || *rolled_up
+                || s.period_end <= revision.created_at
+        });
+        state.set_summary(summary, RevisionSource::Admin, None, Utc::now().naive_utc());
+        Ok(Some(revision))
+    }
+
+    async fn channel_list(&self) -> Result<Vec<u64>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let mut channels = state
+            .messages
+            .iter()
+            .filter_map(|m| m.channel.parse().ok())
+            .collect::<Vec<u64>>();
+        channels.sort();
+        channels.dedup();
+        Ok(channels)
+    }
+
+    async fn archive_messages(
+        &self,
+        channel: u64,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut messages = state.channel_messages(channel);
+        messages.retain(|m| m.date_time < before);
+        if messages.is_empty() {
+            return Ok(0);
+        }
+        let data = compress_messages(&messages)?;
+        state
+            .archive
+            .entry(channel.to_string())
+            .or_default()
+            .push(data);
+
+        let channel = channel.to_string();
+        state
+            .messages
+            .retain(|m| m.channel != channel || m.date_time >= before);
+        Ok(messages.len() as u64)
+    }
+
+    async fn delete_messages(
+        &self,
+        channel: u64,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let channel = channel.to_string();
+        let count = state.messages.len();
+        state
+            .messages
+            .retain(|m| m.channel != channel || m.date_time >= before);
+        Ok((count - state.messages.len()) as u64)
+    }
+
+    async fn get_archived_messages(
+        &self,
+        channel: u64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let mut messages = Vec::new();
+        for batch in state
+            .archive
+            .get(&channel.to_string())
+            .into_iter()
+            .flatten()
+        {
+            messages.extend(decompress_messages(batch)?);
+        }
+        Ok(messages)
+    }
+
+    async fn get_all_messages(
+        &self,
+        filter: &MessageFilter,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        let mut messages = state
+            .messages
+            .iter()
+            .filter(|m| filter.matches(m))
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|m| m.date_time);
+        Ok(messages)
+    }
+
+    async fn get_all_summaries(&self) -> Result<Vec<DbSummary>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.summaries.values().cloned().collect())
+    }
+
+    async fn get_all_period_summaries(&self) -> Result<Vec<DbPeriodSummary>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .period_summaries
+            .iter()
+            .filter(|(_, rolled_up)| !rolled_up)
+            .map(|(s, _)| s.clone())
+            .collect())
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<DbUser>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.users.values().cloned().collect())
+    }
+
+    async fn get_all_user_names(&self) -> Result<Vec<DbSeenName>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .user_names
+            .iter()
+            .map(|((user_id, name), (first_seen, last_seen))| DbSeenName {
+                user_id: user_id.clone(),
+                name: name.clone(),
+                first_seen: *first_seen,
+                last_seen: *last_seen,
+            })
+            .collect())
+    }
+
+    async fn get_all_user_settings(&self) -> Result<Vec<DbUserSettings>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .share_profile
+            .iter()
+            .map(|(id, share_profile)| DbUserSettings {
+                id: id.clone(),
+                share_profile: *share_profile,
+            })
+            .collect())
+    }
+
+    async fn import_message(&self, message: &DbMessage) -> Result<bool, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let exists = state.messages.iter().any(|m| {
+            m.channel == message.channel
+                && m.date_time == message.date_time
+                && m.sender_id == message.sender_id
+                && m.message == message.message
+        });
+        if !exists {
+            state.messages.push(message.clone());
+        }
+        Ok(!exists)
+    }
+
+    async fn import_summary(&self, summary: &DbSummary) -> Result<bool, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(current) = state.summaries.get(&summary.channel) {
+            if current.last_update >= summary.last_update {
+                return Ok(false);
+            }
+        }
+        state.set_summary(
+            summary.clone(),
+            RevisionSource::Import,
+            None,
+            summary.last_update,
+        );
+        Ok(true)
+    }
+
+    async fn import_period_summary(
+        &self,
+        summary: &DbPeriodSummary,
+    ) -> Result<bool, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let exists = state.period_summaries.iter().any(|(s, _)| {
+            s.channel == summary.channel
+                && s.level == summary.level
+                && s.period_start == summary.period_start
+                && s.period_end == summary.period_end
+        });
+        if !exists {
+            state.add_period_summary(summary);
+        }
+        Ok(!exists)
+    }
+
+    async fn import_user(&self, user: &DbUser) -> Result<bool, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let key = (user.id.clone(), user.scope.clone());
+        if let Some(current) = state.users.get(&key) {
+            if current.last_update >= user.last_update {
+                return Ok(false);
+            }
+        }
+        state.set_user(user.clone(), RevisionSource::Import, None);
+        Ok(true)
+    }
+
+    async fn import_user_name(&self, name: &DbSeenName) -> Result<bool, sqlx::error::Error> {
+        let mut state = self.state.lock().unwrap();
+        let key = (name.user_id.clone(), name.name.clone());
+        let seen = match state.user_names.get(&key) {
+            Some((first_seen, last_seen)) => (
+                (*first_seen).min(name.first_seen),
+                (*last_seen).max(name.last_seen),
+            ),
+            None => (name.first_seen, name.last_seen),
+        };
+        let changed = state.user_names.insert(key, seen) != Some(seen);
+        Ok(changed)
+    }
+
+    async fn get_access_rules(&self) -> Result<Vec<DbAccessRule>, sqlx::error::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .access_rules
+            .iter()
+            .map(|((scope, target), access)| DbAccessRule {
+                scope: scope.clone(),
+                target: target.clone(),
+                access: access.clone(),
+            })
+            .collect())
+    }
+
+    async fn set_access_rule(
+        &self,
+        scope: &str,
+        target: u64,
+        access: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        self.state
+            .lock()
+            .unwrap()
+            .access_rules
+            .insert((scope.to_string(), target.to_string()), access.to_string());
+        Ok(())
+    }
+
+    async fn delete_access_rule(&self, scope: &str, target: u64) -> Result<(), sqlx::error::Error> {
+        self.state
+            .lock()
+            .unwrap()
+            .access_rules
+            .remove(&(scope.to_string(), target.to_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,587 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use crate::config::DatabaseConfig;
+
+#[cfg(test)]
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+#[cfg(test)]
+mod tests;
+
+#[cfg(test)]
+pub use memory::MemoryDatabase;
+#[cfg(feature = "postgres")]
+pub use postgres::PgDatabase;
+pub use sqlite::SqliteDatabase;
+
+/// Id of Kasumi's own messages and profile.
+pub const KASUMI_ID: &str = "kasumi";
+
+/// Where a user profile applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileScope {
+    /// Shared everywhere, only used for users who agreed to it.
+    Global,
+    Guild(u64),
+    Direct,
+    /// Profiles from before scopes existed.
+    Legacy,
+}
+
+impl ProfileScope {
+    pub fn of(guild: Option<u64>) -> Self {
+        guild.map_or(ProfileScope::Direct, ProfileScope::Guild)
+    }
+}
+
+impl fmt::Display for ProfileScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileScope::Global => f.write_str("global"),
+            ProfileScope::Guild(guild) => write!(f, "{}", guild),
+            ProfileScope::Direct => f.write_str("dm"),
+            ProfileScope::Legacy => f.write_str("legacy"),
+        }
+    }
+}
+
+impl FromStr for ProfileScope {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(ProfileScope::Global),
+            "dm" => Ok(ProfileScope::Direct),
+            "legacy" => Ok(ProfileScope::Legacy),
+            guild => Ok(ProfileScope::Guild(guild.parse()?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct DbMessage {
+    pub channel: String,
+    /// Profile scope of the channel, see [`ProfileScope`].
+    pub scope: String,
+    pub sender_id: String,
+    pub sender: String,
+    pub message: String,
+    pub date_time: NaiveDateTime,
+    /// Discord id of the message, if known.
+    #[serde(default)]
+    pub message_id: Option<String>,
+    /// The message as it came from Discord, before its markup was made readable.
+    #[serde(default)]
+    pub raw_message: Option<String>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct DbUser {
+    pub id: String,
+    pub scope: String,
+    pub name: String,
+    pub info: String,
+    pub last_update: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct DbSummary {
+    pub channel: String,
+    pub summary: String,
+    pub last_update: NaiveDateTime,
+}
+
+impl Default for DbSummary {
+    fn default() -> Self {
+        Self {
+            channel: String::new(),
+            summary: String::new(),
+            last_update: NaiveDateTime::from_timestamp_millis(0).unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbUserName {
+    pub user_id: String,
+    pub name: String,
+}
+
+/// A name a user was seen with, with when.
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct DbSeenName {
+    pub user_id: String,
+    pub name: String,
+    pub first_seen: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct DbUserSettings {
+    pub id: String,
+    pub share_profile: bool,
+}
+
+/// How much of a channel's history a [`DbPeriodSummary`] covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SummaryLevel {
+    /// One summarizer pass.
+    Session,
+    Daily,
+    Weekly,
+    /// Everything older than the weekly summaries.
+    LongTerm,
+}
+
+impl fmt::Display for SummaryLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SummaryLevel::Session => "session",
+            SummaryLevel::Daily => "daily",
+            SummaryLevel::Weekly => "weekly",
+            SummaryLevel::LongTerm => "long_term",
+        })
+    }
+}
+
+impl FromStr for SummaryLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "session" => Ok(SummaryLevel::Session),
+            "daily" => Ok(SummaryLevel::Daily),
+            "weekly" => Ok(SummaryLevel::Weekly),
+            "long_term" => Ok(SummaryLevel::LongTerm),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A summary of part of a channel's history.
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct DbPeriodSummary {
+    #[serde(skip)]
+    pub id: i64,
+    pub channel: String,
+    /// See [`SummaryLevel`].
+    pub level: String,
+    pub period_start: NaiveDateTime,
+    pub period_end: NaiveDateTime,
+    pub summary: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Where a summary or profile revision came from.
+/// What was stored before revisions were kept has the source `legacy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevisionSource {
+    Summarizer,
+    Admin,
+    Import,
+}
+
+impl fmt::Display for RevisionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RevisionSource::Summarizer => "summarizer",
+            RevisionSource::Admin => "admin",
+            RevisionSource::Import => "import",
+        })
+    }
+}
+
+/// A version of a channel summary.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbSummaryRevision {
+    pub id: i64,
+    pub channel: String,
+    pub summary: String,
+    /// See [`RevisionSource`].
+    pub source: String,
+    /// The whole model response the summary was parsed from.
+    pub model_response: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// A version of a user profile.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbUserRevision {
+    pub id: i64,
+    pub user_id: String,
+    pub scope: String,
+    pub name: String,
+    pub info: String,
+    /// See [`RevisionSource`].
+    pub source: String,
+    /// The whole model response the profile was parsed from.
+    pub model_response: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Profile changes from the summarizer waiting for review.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbProfileProposal {
+    pub id: i64,
+    pub user_id: String,
+    pub scope: String,
+    /// The name of the user when the changes were proposed.
+    pub name: String,
+    /// JSON object of the proposed fields.
+    pub changes: String,
+    pub model_response: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbAccessRule {
+    pub scope: String,
+    pub target: String,
+    pub access: String,
+}
+
+/// Selects messages by channel, guild and date. `since` is inclusive, `until` exclusive.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    pub channel: Option<u64>,
+    pub guild: Option<u64>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+impl MessageFilter {
+    pub fn matches(&self, message: &DbMessage) -> bool {
+        self.channel
+            .is_none_or(|c| message.channel == c.to_string())
+            && self.guild.is_none_or(|g| message.scope == g.to_string())
+            && self.includes(message.date_time)
+    }
+
+    /// Whether the time is within the date range.
+    pub fn includes(&self, date_time: NaiveDateTime) -> bool {
+        self.since.is_none_or(|since| date_time >= since)
+            && self.until.is_none_or(|until| date_time < until)
+    }
+}
+
+/// Full-text search over messages.
+#[derive(Debug, Clone, Default)]
+pub struct MessageSearch {
+    pub text: String,
+    pub filter: MessageFilter,
+    pub sender_id: Option<String>,
+    pub limit: i64,
+}
+
+impl MessageSearch {
+    /// The lowercase words of the search text. Every one of them has to match.
+    pub fn terms(&self) -> Vec<String> {
+        self.text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|term| !term.is_empty())
+            .map(|term| term.to_lowercase())
+            .collect()
+    }
+}
+
+/// Message, user, summary and access rule storage.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Messages of a channel after `after`, oldest first.
+    async fn get_messages_by_date(
+        &self,
+        channel: u64,
+        after: NaiveDateTime,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error>;
+
+    /// The last `count` messages of a channel, oldest first.
+    async fn get_messages_by_count(
+        &self,
+        channel: u64,
+        count: i64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error>;
+
+    async fn add_message(&self, message: &DbMessage) -> Result<(), sqlx::error::Error>;
+
+    /// Messages containing words starting with every term of the search, best matches first.
+    async fn search_messages(
+        &self,
+        search: &MessageSearch,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error>;
+
+    /// Returns the profiles of the users in `scope`, plus the global profiles of
+    /// Kasumi and of the users who share theirs.
+    async fn get_users(
+        &self,
+        scope: ProfileScope,
+        ids: &[String],
+    ) -> Result<Vec<DbUser>, sqlx::error::Error>;
+
+    /// Stores the profile and records it as a new revision.
+    async fn update_user(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        name: &str,
+        info: &str,
+        source: RevisionSource,
+        model_response: Option<&str>,
+    ) -> Result<(), sqlx::error::Error>;
+
+    async fn shares_profile(&self, id: &str) -> Result<bool, sqlx::error::Error>;
+
+    async fn set_share_profile(&self, id: &str, share: bool) -> Result<(), sqlx::error::Error>;
+
+    /// Records the name a user is currently seen with.
+    /// A profile stored under their username before users had ids is moved to their id,
+    /// and a profile from before scopes is moved to the first guild they show up in.
+    /// Revisions move along with the profile.
+    async fn touch_user(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        username: &str,
+        display_name: &str,
+    ) -> Result<(), sqlx::error::Error>;
+
+    /// Returns every name the users were seen with, most recent first.
+    async fn get_user_names(&self, ids: &[String]) -> Result<Vec<DbUserName>, sqlx::error::Error>;
+
+    /// The last `limit` revisions of a profile, newest first.
+    async fn get_user_history(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        limit: i64,
+    ) -> Result<Vec<DbUserRevision>, sqlx::error::Error>;
+
+    async fn get_user_revision(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbUserRevision>, sqlx::error::Error>;
+
+    /// Makes a revision the current profile again, recorded as an admin revision.
+    /// Returns the restored revision, if it exists.
+    async fn rollback_user(&self, id: i64) -> Result<Option<DbUserRevision>, sqlx::error::Error>;
+
+    /// Queues profile changes for review. The id of the proposal is ignored; returns the new one.
+    async fn add_profile_proposal(
+        &self,
+        proposal: &DbProfileProposal,
+    ) -> Result<i64, sqlx::error::Error>;
+
+    /// Pending proposals of one user, or of everyone, oldest first.
+    async fn get_profile_proposals(
+        &self,
+        user_id: Option<&str>,
+    ) -> Result<Vec<DbProfileProposal>, sqlx::error::Error>;
+
+    async fn get_profile_proposal(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbProfileProposal>, sqlx::error::Error>;
+
+    /// Returns whether the proposal existed.
+    async fn delete_profile_proposal(&self, id: i64) -> Result<bool, sqlx::error::Error>;
+
+    async fn get_summary(&self, channel: u64) -> Result<Option<DbSummary>, sqlx::error::Error>;
+
+    /// Stores the summary and records it as a new revision. With `session_start`, it is also
+    /// kept as the session summary of the messages from then on.
+    async fn update_summary(
+        &self,
+        channel: u64,
+        summary: &str,
+        source: RevisionSource,
+        model_response: Option<&str>,
+        session_start: Option<NaiveDateTime>,
+    ) -> Result<(), sqlx::error::Error>;
+
+    /// The summaries of a channel that are not rolled up yet, oldest first.
+    async fn get_period_summaries(
+        &self,
+        channel: u64,
+    ) -> Result<Vec<DbPeriodSummary>, sqlx::error::Error>;
+
+    /// Adds a summary that replaces the summaries with the ids in `parts`.
+    /// The id of the summary is ignored; returns the new one.
+    async fn roll_up_summaries(
+        &self,
+        parts: &[i64],
+        summary: &DbPeriodSummary,
+    ) -> Result<i64, sqlx::error::Error>;
+
+    /// The last `limit` revisions of a channel summary, newest first.
+    async fn get_summary_history(
+        &self,
+        channel: u64,
+        limit: i64,
+    ) -> Result<Vec<DbSummaryRevision>, sqlx::error::Error>;
+
+    async fn get_summary_revision(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbSummaryRevision>, sqlx::error::Error>;
+
+    /// Makes a revision the current summary of its channel again, recorded as an admin revision.
+    /// The summary gets the time of the revision back, so the messages since then are
+    /// in prompts again, and the session summaries since then are dropped.
+    /// Returns the restored revision, if it exists.
+    async fn rollback_summary(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbSummaryRevision>, sqlx::error::Error>;
+
+    /// Every channel that has messages.
+    async fn channel_list(&self) -> Result<Vec<u64>, sqlx::error::Error>;
+
+    /// Moves the messages of a channel from before `before` into one compressed archive batch.
+    /// Returns how many were moved.
+    async fn archive_messages(
+        &self,
+        channel: u64,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::error::Error>;
+
+    /// Deletes the messages of a channel from before `before`. Returns how many were deleted.
+    async fn delete_messages(
+        &self,
+        channel: u64,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::error::Error>;
+
+    /// Archived messages of a channel, oldest first.
+    async fn get_archived_messages(
+        &self,
+        channel: u64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error>;
+
+    /// Messages matching the filter, oldest first.
+    async fn get_all_messages(
+        &self,
+        filter: &MessageFilter,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error>;
+
+    async fn get_all_summaries(&self) -> Result<Vec<DbSummary>, sqlx::error::Error>;
+
+    /// Every summary that is not rolled up yet.
+    async fn get_all_period_summaries(&self) -> Result<Vec<DbPeriodSummary>, sqlx::error::Error>;
+
+    /// Profiles of every user in every scope.
+    async fn get_all_users(&self) -> Result<Vec<DbUser>, sqlx::error::Error>;
+
+    async fn get_all_user_names(&self) -> Result<Vec<DbSeenName>, sqlx::error::Error>;
+
+    async fn get_all_user_settings(&self) -> Result<Vec<DbUserSettings>, sqlx::error::Error>;
+
+    /// Adds the message unless the same one is already stored. Returns whether it was added.
+    async fn import_message(&self, message: &DbMessage) -> Result<bool, sqlx::error::Error>;
+
+    /// Stores the summary unless the channel has a newer one, recording an import revision.
+    /// Returns whether it was stored.
+    async fn import_summary(&self, summary: &DbSummary) -> Result<bool, sqlx::error::Error>;
+
+    /// Adds the summary unless the channel has one of the same level and period.
+    /// Returns whether it was added.
+    async fn import_period_summary(
+        &self,
+        summary: &DbPeriodSummary,
+    ) -> Result<bool, sqlx::error::Error>;
+
+    /// Stores the profile unless the user has a newer one in that scope,
+    /// recording an import revision. Returns whether it was stored.
+    async fn import_user(&self, user: &DbUser) -> Result<bool, sqlx::error::Error>;
+
+    /// Adds the name, widening the time it was seen if it is already known.
+    /// Returns whether anything changed.
+    async fn import_user_name(&self, name: &DbSeenName) -> Result<bool, sqlx::error::Error>;
+
+    async fn get_access_rules(&self) -> Result<Vec<DbAccessRule>, sqlx::error::Error>;
+
+    async fn set_access_rule(
+        &self,
+        scope: &str,
+        target: u64,
+        access: &str,
+    ) -> Result<(), sqlx::error::Error>;
+
+    async fn delete_access_rule(&self, scope: &str, target: u64) -> Result<(), sqlx::error::Error>;
+}
+
+/// Packs messages into an archive batch.
+fn compress_messages(messages: &[DbMessage]) -> Result<Vec<u8>, sqlx::error::Error> {
+    let json = serde_json::to_vec(messages).map_err(std::io::Error::from)?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json)?;
+    Ok(encoder.finish()?)
+}
+
+fn decompress_messages(data: &[u8]) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+    let mut json = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut json)?;
+    serde_json::from_slice(&json).map_err(|e| sqlx::error::Error::Decode(e.into()))
+}
+
+/// Shared handle to the storage backend.
+#[derive(Clone)]
+pub struct Database {
+    storage: Arc<dyn Storage>,
+}
+
+impl Deref for Database {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        self.storage.as_ref()
+    }
+}
+
+impl Database {
+    /// Picks the backend by the scheme of the database url.
+    pub async fn new(config: &DatabaseConfig) -> Result<Self, sqlx::error::Error> {
+        if config.url.starts_with("postgres:") || config.url.starts_with("postgresql:") {
+            #[cfg(feature = "postgres")]
+            return Ok(Self::with_storage(PgDatabase::new(config).await?));
+            #[cfg(not(feature = "postgres"))]
+            return Err(sqlx::error::Error::Configuration(
+                "PostgreSQL support requires the `postgres` feature".into(),
+            ));
+        }
+        Ok(Self::with_storage(SqliteDatabase::new(config).await?))
+    }
+
+    pub fn with_storage(storage: impl Storage + 'static) -> Self {
+        Self {
+            storage: Arc::new(storage),
+        }
+    }
+
+    /// Messages of a channel after `after`, or the last `min_count` if there are fewer.
+    pub async fn get_messages(
+        &self,
+        channel: u64,
+        after: NaiveDateTime,
+        min_count: i64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let messages = self.get_messages_by_date(channel, after).await?;
+        let messages = if messages.len() < min_count as usize {
+            self.get_messages_by_count(channel, min_count).await?
+        } else {
+            messages
+        };
+        Ok(messages)
+    }
+}
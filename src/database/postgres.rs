@@ -0,0 +1,1027 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions, Postgres};
+use sqlx::Transaction;
+
+use super::{
+    compress_messages, decompress_messages, DbAccessRule, DbMessage, DbPeriodSummary,
+    DbProfileProposal, DbSeenName, DbSummary, DbSummaryRevision, DbUser, DbUserName,
+    DbUserRevision, DbUserSettings, MessageFilter, MessageSearch, ProfileScope, RevisionSource,
+    Storage, SummaryLevel, KASUMI_ID,
+};
+use crate::config::DatabaseConfig;
+
+/// PostgreSQL backend. The queries mirror the SQLite ones, but are checked at runtime
+/// since the query macros can only check against one database.
+#[derive(Clone)]
+pub struct PgDatabase {
+    pool: PgPool,
+}
+
+impl PgDatabase {
+    pub async fn new(config: &DatabaseConfig) -> Result<Self, sqlx::error::Error> {
+        let options = PgConnectOptions::from_str(&config.url)?;
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .min_connections(1)
+            .acquire_timeout(config.busy_timeout())
+            .connect_with(options)
+            .await?;
+        sqlx::migrate!("./migrations_postgres").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+async fn add_summary_revision(
+    tx: &mut Transaction<'_, Postgres>,
+    summary: &DbSummary,
+    source: RevisionSource,
+    model_response: Option<&str>,
+) -> Result<(), sqlx::error::Error> {
+    sqlx::query(
+        r#"
+INSERT INTO summary_revisions (channel, summary, source, model_response, created_at)
+VALUES ($1, $2, $3, $4, $5)"#,
+    )
+    .bind(&summary.channel)
+    .bind(&summary.summary)
+    .bind(source.to_string())
+    .bind(model_response)
+    .bind(summary.last_update)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+async fn add_user_revision(
+    tx: &mut Transaction<'_, Postgres>,
+    user: &DbUser,
+    source: RevisionSource,
+    model_response: Option<&str>,
+) -> Result<(), sqlx::error::Error> {
+    sqlx::query(
+        r#"
+INSERT INTO user_revisions (user_id, scope, name, info, source, model_response, created_at)
+VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+    )
+    .bind(&user.id)
+    .bind(&user.scope)
+    .bind(&user.name)
+    .bind(&user.info)
+    .bind(source.to_string())
+    .bind(model_response)
+    .bind(user.last_update)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+async fn set_summary(
+    tx: &mut Transaction<'_, Postgres>,
+    summary: &DbSummary,
+) -> Result<(), sqlx::error::Error> {
+    sqlx::query(
+        r#"
+INSERT INTO channels (channel, summary, last_update)
+VALUES ($1, $2, $3)
+ON CONFLICT (channel) DO UPDATE
+SET summary = excluded.summary, last_update = excluded.last_update"#,
+    )
+    .bind(&summary.channel)
+    .bind(&summary.summary)
+    .bind(summary.last_update)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+async fn add_period_summary(
+    tx: &mut Transaction<'_, Postgres>,
+    summary: &DbPeriodSummary,
+) -> Result<i64, sqlx::error::Error> {
+    sqlx::query_scalar(
+        r#"
+INSERT INTO period_summaries (channel, level, period_start, period_end, summary, created_at)
+VALUES ($1, $2, $3, $4, $5, $6)
+RETURNING id"#,
+    )
+    .bind(&summary.channel)
+    .bind(&summary.level)
+    .bind(summary.period_start)
+    .bind(summary.period_end)
+    .bind(&summary.summary)
+    .bind(summary.created_at)
+    .fetch_one(&mut *tx)
+    .await
+}
+
+async fn set_user(
+    tx: &mut Transaction<'_, Postgres>,
+    user: &DbUser,
+) -> Result<(), sqlx::error::Error> {
+    sqlx::query(
+        r#"
+INSERT INTO users (id, scope, name, info, last_update)
+VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (id, scope) DO UPDATE
+SET name = excluded.name, info = excluded.info, last_update = excluded.last_update"#,
+    )
+    .bind(&user.id)
+    .bind(&user.scope)
+    .bind(&user.name)
+    .bind(&user.info)
+    .bind(user.last_update)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for PgDatabase {
+    async fn get_messages_by_date(
+        &self,
+        channel: u64,
+        after: NaiveDateTime,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let mut messages: Vec<DbMessage> = sqlx::query_as(
+            r#"
+SELECT channel, scope, sender_id, sender, message, date_time, message_id, raw_message
+FROM messages
+WHERE channel = $1 AND date_time > $2
+ORDER BY date_time DESC"#,
+        )
+        .bind(channel.to_string())
+        .bind(after)
+        .fetch_all(&self.pool)
+        .await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn get_messages_by_count(
+        &self,
+        channel: u64,
+        count: i64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let mut messages: Vec<DbMessage> = sqlx::query_as(
+            r#"
+SELECT channel, scope, sender_id, sender, message, date_time, message_id, raw_message
+FROM messages
+WHERE channel = $1
+ORDER BY date_time DESC
+LIMIT $2"#,
+        )
+        .bind(channel.to_string())
+        .bind(count)
+        .fetch_all(&self.pool)
+        .await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn add_message(&self, message: &DbMessage) -> Result<(), sqlx::error::Error> {
+        sqlx::query(
+            r#"
+INSERT INTO messages ( channel, scope, sender_id, sender, message, date_time, message_id, raw_message )
+VALUES ( $1, $2, $3, $4, $5, $6, $7, $8 )"#,
+        )
+        .bind(&message.channel)
+        .bind(&message.scope)
+        .bind(&message.sender_id)
+        .bind(&message.sender)
+        .bind(&message.message)
+        .bind(message.date_time)
+        .bind(&message.message_id)
+        .bind(&message.raw_message)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn search_messages(
+        &self,
+        search: &MessageSearch,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let terms = search.terms();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        // terms are alphanumeric, so they can't contain tsquery syntax
+        let query = terms
+            .iter()
+            .map(|term| format!("{}:*", term))
+            .collect::<Vec<_>>()
+            .join(" & ");
+
+        sqlx::query_as(
+            r#"
+SELECT channel, scope, sender_id, sender, message, date_time, message_id, raw_message
+FROM messages
+WHERE to_tsvector('simple', message) @@ to_tsquery('simple', $1)
+AND ($2::TEXT IS NULL OR channel = $2)
+AND ($3::TEXT IS NULL OR scope = $3)
+AND ($4::TEXT IS NULL OR sender_id = $4)
+AND ($5::TIMESTAMP IS NULL OR date_time >= $5)
+AND ($6::TIMESTAMP IS NULL OR date_time < $6)
+ORDER BY ts_rank(to_tsvector('simple', message), to_tsquery('simple', $1)) DESC, date_time DESC
+LIMIT $7"#,
+        )
+        .bind(query)
+        .bind(search.filter.channel.map(|c| c.to_string()))
+        .bind(search.filter.guild.map(|g| g.to_string()))
+        .bind(&search.sender_id)
+        .bind(search.filter.since)
+        .bind(search.filter.until)
+        .bind(search.limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_users(
+        &self,
+        scope: ProfileScope,
+        ids: &[String],
+    ) -> Result<Vec<DbUser>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT users.id, users.scope, users.name, users.info, users.last_update
+FROM users
+LEFT JOIN user_settings ON user_settings.id = users.id
+WHERE users.id = ANY($1)
+AND (users.scope = $2
+     OR (users.scope = 'global' AND (users.id = $3 OR user_settings.share_profile)))"#,
+        )
+        .bind(ids)
+        .bind(scope.to_string())
+        .bind(KASUMI_ID)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn update_user(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        name: &str,
+        info: &str,
+        source: RevisionSource,
+        model_response: Option<&str>,
+    ) -> Result<(), sqlx::error::Error> {
+        let user = DbUser {
+            id: id.to_string(),
+            scope: scope.to_string(),
+            name: name.to_string(),
+            info: info.to_string(),
+            last_update: Utc::now().naive_utc(),
+        };
+
+        let mut tx = self.pool.begin().await?;
+        set_user(&mut tx, &user).await?;
+        add_user_revision(&mut tx, &user, source, model_response).await?;
+        tx.commit().await
+    }
+
+    async fn shares_profile(&self, id: &str) -> Result<bool, sqlx::error::Error> {
+        let shared: Option<bool> = sqlx::query_scalar(
+            r#"
+SELECT share_profile
+FROM user_settings WHERE id = $1"#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(shared.unwrap_or(false))
+    }
+
+    async fn set_share_profile(&self, id: &str, share: bool) -> Result<(), sqlx::error::Error> {
+        sqlx::query(
+            r#"
+INSERT INTO user_settings (id, share_profile)
+VALUES ($1, $2)
+ON CONFLICT (id) DO UPDATE SET share_profile = excluded.share_profile"#,
+        )
+        .bind(id)
+        .bind(share)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn touch_user(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        username: &str,
+        display_name: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        let now = Utc::now().naive_utc();
+        let legacy_id = format!("legacy:{}", username);
+        let scope_key = scope.to_string();
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+INSERT INTO user_names (user_id, name, first_seen, last_seen)
+VALUES ($1, $2, $3, $3)
+ON CONFLICT (user_id, name) DO UPDATE SET last_seen = excluded.last_seen"#,
+        )
+        .bind(id)
+        .bind(display_name)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+UPDATE users SET id = $1
+WHERE id = $2
+AND NOT EXISTS (SELECT 1 FROM users AS other WHERE other.id = $1 AND other.scope = users.scope)"#,
+        )
+        .bind(id)
+        .bind(&legacy_id)
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+UPDATE user_revisions SET user_id = $1
+WHERE user_id = $2
+AND NOT EXISTS (SELECT 1 FROM users WHERE users.id = $2 AND users.scope = user_revisions.scope)"#,
+        )
+        .bind(id)
+        .bind(&legacy_id)
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+UPDATE messages SET sender_id = $1
+WHERE sender_id = $2"#,
+        )
+        .bind(id)
+        .bind(&legacy_id)
+        .execute(&mut tx)
+        .await?;
+
+        if let ProfileScope::Guild(_) = scope {
+            sqlx::query(
+                r#"
+UPDATE users SET scope = $2
+WHERE id = $1 AND scope = 'legacy'
+AND NOT EXISTS (SELECT 1 FROM users AS other WHERE other.id = $1 AND other.scope = $2)"#,
+            )
+            .bind(id)
+            .bind(&scope_key)
+            .execute(&mut tx)
+            .await?;
+
+            sqlx::query(
+                r#"
+UPDATE user_revisions SET scope = $2
+WHERE user_id = $1 AND scope = 'legacy'
+AND NOT EXISTS (SELECT 1 FROM users WHERE users.id = $1 AND users.scope = 'legacy')"#,
+            )
+            .bind(id)
+            .bind(&scope_key)
+            .execute(&mut tx)
+            .await?;
+        }
+
+        sqlx::query(
+            r#"
+UPDATE users SET name = $2
+WHERE id = $1 AND scope IN ($3, 'global')"#,
+        )
+        .bind(id)
+        .bind(display_name)
+        .bind(&scope_key)
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    async fn get_user_names(&self, ids: &[String]) -> Result<Vec<DbUserName>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT user_id, name
+FROM user_names WHERE user_id = ANY($1)
+ORDER BY last_seen DESC"#,
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_user_history(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        limit: i64,
+    ) -> Result<Vec<DbUserRevision>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, user_id, scope, name, info, source, model_response, created_at
+FROM user_revisions
+WHERE user_id = $1 AND scope = $2
+ORDER BY id DESC
+LIMIT $3"#,
+        )
+        .bind(id)
+        .bind(scope.to_string())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_user_revision(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbUserRevision>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, user_id, scope, name, info, source, model_response, created_at
+FROM user_revisions
+WHERE id = $1"#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn rollback_user(&self, id: i64) -> Result<Option<DbUserRevision>, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let revision: Option<DbUserRevision> = sqlx::query_as(
+            r#"
+SELECT id, user_id, scope, name, info, source, model_response, created_at
+FROM user_revisions
+WHERE id = $1"#,
+        )
+        .bind(id)
+        .fetch_optional(&mut tx)
+        .await?;
+        let Some(revision) = revision else {
+            return Ok(None);
+        };
+
+        let user = DbUser {
+            id: revision.user_id.clone(),
+            scope: revision.scope.clone(),
+            name: revision.name.clone(),
+            info: revision.info.clone(),
+            last_update: Utc::now().naive_utc(),
+        };
+        set_user(&mut tx, &user).await?;
+        add_user_revision(&mut tx, &user, RevisionSource::Admin, None).await?;
+        tx.commit().await?;
+        Ok(Some(revision))
+    }
+
+    async fn add_profile_proposal(
+        &self,
+        proposal: &DbProfileProposal,
+    ) -> Result<i64, sqlx::error::Error> {
+        sqlx::query_scalar(
+            r#"
+INSERT INTO profile_proposals (user_id, scope, name, changes, model_response, created_at)
+VALUES ($1, $2, $3, $4, $5, $6)
+RETURNING id"#,
+        )
+        .bind(&proposal.user_id)
+        .bind(&proposal.scope)
+        .bind(&proposal.name)
+        .bind(&proposal.changes)
+        .bind(&proposal.model_response)
+        .bind(proposal.created_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn get_profile_proposals(
+        &self,
+        user_id: Option<&str>,
+    ) -> Result<Vec<DbProfileProposal>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, user_id, scope, name, changes, model_response, created_at
+FROM profile_proposals
+WHERE ($1::TEXT IS NULL OR user_id = $1)
+ORDER BY id"#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_profile_proposal(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbProfileProposal>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, user_id, scope, name, changes, model_response, created_at
+FROM profile_proposals
+WHERE id = $1"#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn delete_profile_proposal(&self, id: i64) -> Result<bool, sqlx::error::Error> {
+        let result = sqlx::query(
+            r#"
+DELETE FROM profile_proposals
+WHERE id = $1"#,
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_summary(&self, channel: u64) -> Result<Option<DbSummary>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT channel, summary, last_update
+FROM channels WHERE channel = $1"#,
+        )
+        .bind(channel.to_string())
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn update_summary(
+        &self,
+        channel: u64,
+        summary: &str,
+        source: RevisionSource,
+        model_response: Option<&str>,
+        session_start: Option<NaiveDateTime>,
+    ) -> Result<(), sqlx::error::Error> {
+        let summary = DbSummary {
+            channel: channel.to_string(),
+            summary: summary.to_string(),
+            last_update: Utc::now().naive_utc(),
+        };
+
+        let mut tx = self.pool.begin().await?;
+        set_summary(&mut tx, &summary).await?;
+        add_summary_revision(&mut tx, &summary, source, model_response).await?;
+        if let Some(period_start) = session_start {
+            let session = DbPeriodSummary {
+                id: 0,
+                channel: summary.channel.clone(),
+                level: SummaryLevel::Session.to_string(),
+                period_start,
+                period_end: summary.last_update,
+                summary: summary.summary.clone(),
+                created_at: summary.last_update,
+            };
+            add_period_summary(&mut tx, &session).await?;
+        }
+        tx.commit().await
+    }
+
+    async fn get_period_summaries(
+        &self,
+        channel: u64,
+    ) -> Result<Vec<DbPeriodSummary>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, channel, level, period_start, period_end, summary, created_at
+FROM period_summaries
+WHERE channel = $1 AND NOT rolled_up
+ORDER BY period_start, id"#,
+        )
+        .bind(channel.to_string())
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn roll_up_summaries(
+        &self,
+        parts: &[i64],
+        summary: &DbPeriodSummary,
+    ) -> Result<i64, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let id = add_period_summary(&mut tx, summary).await?;
+        sqlx::query(
+            r#"
+UPDATE period_summaries
+SET rolled_up = TRUE
+WHERE id = ANY($1)"#,
+        )
+        .bind(parts)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(id)
+    }
+
+    async fn get_summary_history(
+        &self,
+        channel: u64,
+        limit: i64,
+    ) -> Result<Vec<DbSummaryRevision>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, channel, summary, source, model_response, created_at
+FROM summary_revisions
+WHERE channel = $1
+ORDER BY id DESC
+LIMIT $2"#,
+        )
+        .bind(channel.to_string())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_summary_revision(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbSummaryRevision>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, channel, summary, source, model_response, created_at
+FROM summary_revisions
+WHERE id = $1"#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn rollback_summary(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbSummaryRevision>, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let revision: Option<DbSummaryRevision> = sqlx::query_as(
+            r#"
+SELECT id, channel, summary, source, model_response, created_at
+FROM summary_revisions
+WHERE id = $1"#,
+        )
+        .bind(id)
+        .fetch_optional(&mut tx)
+        .await?;
+        let Some(revision) = revision else {
+            return Ok(None);
+        };
+
+        let summary = DbSummary {
+            channel: revision.channel.clone(),
+            summary: revision.summary.clone(),
+            last_update: revision.created_at,
+        };
+        set_summary(&mut tx, &summary).await?;
+        sqlx::query(
+            r#"
+DELETE FROM period_summaries
+WHERE channel = $1 AND level = $2 AND NOT rolled_up AND period_end > $3"#,
+        )
+        .bind(&summary.channel)
+        .bind(SummaryLevel::Session.to_string())
+        .bind(summary.last_update)
+        .execute(&mut tx)
+        .await?;
+        let restored = DbSummary {
+            last_update: Utc::now().naive_utc(),
+            ..summary
+        };
+        add_summary_revision(&mut tx, &restored, RevisionSource::Admin, None).await?;
+        tx.commit().await?;
+        Ok(Some(revision))
+    }
+
+    async fn channel_list(&self) -> Result<Vec<u64>, sqlx::error::Error> {
+        let channels: Vec<String> = sqlx::query_scalar(
+            r#"
+SELECT DISTINCT channel
+FROM messages"#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(channels.iter().filter_map(|s| s.parse().ok()).collect())
+    }
+
+    async fn archive_messages(
+        &self,
+        channel: u64,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::error::Error> {
+        let channel = channel.to_string();
+        let mut tx = self.pool.begin().await?;
+        let messages: Vec<DbMessage> = sqlx::query_as(
+            r#"
+SELECT channel, scope, sender_id, sender, message, date_time, message_id, raw_message
+FROM messages
+WHERE channel = $1 AND date_time < $2
+ORDER BY date_time
+FOR UPDATE"#,
+        )
+        .bind(&channel)
+        .bind(before)
+        .fetch_all(&mut tx)
+        .await?;
+        let (Some(first), Some(last)) = (messages.first(), messages.last()) else {
+            return Ok(0);
+        };
+
+        sqlx::query(
+            r#"
+INSERT INTO message_archive (channel, first_date_time, last_date_time, message_count, data, archived_at)
+VALUES ($1, $2, $3, $4, $5, $6)"#,
+        )
+        .bind(&channel)
+        .bind(first.date_time)
+        .bind(last.date_time)
+        .bind(messages.len() as i64)
+        .bind(compress_messages(&messages)?)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut tx)
+        .await?;
+
+        let deleted = sqlx::query(
+            r#"
+DELETE FROM messages
+WHERE channel = $1 AND date_time < $2"#,
+        )
+        .bind(&channel)
+        .bind(before)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn delete_messages(
+        &self,
+        channel: u64,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::error::Error> {
+        let result = sqlx::query(
+            r#"
+DELETE FROM messages
+WHERE channel = $1 AND date_time < $2"#,
+        )
+        .bind(channel.to_string())
+        .bind(before)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_archived_messages(
+        &self,
+        channel: u64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let batches: Vec<Vec<u8>> = sqlx::query_scalar(
+            r#"
+SELECT data
+FROM message_archive
+WHERE channel = $1
+ORDER BY first_date_time"#,
+        )
+        .bind(channel.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for batch in batches {
+            messages.extend(decompress_messages(&batch)?);
+        }
+        Ok(messages)
+    }
+
+    async fn get_all_messages(
+        &self,
+        filter: &MessageFilter,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT channel, scope, sender_id, sender, message, date_time, message_id, raw_message
+FROM messages
+WHERE ($1::TEXT IS NULL OR channel = $1)
+AND ($2::TEXT IS NULL OR scope = $2)
+AND ($3::TIMESTAMP IS NULL OR date_time >= $3)
+AND ($4::TIMESTAMP IS NULL OR date_time < $4)
+ORDER BY date_time"#,
+        )
+        .bind(filter.channel.map(|c| c.to_string()))
+        .bind(filter.guild.map(|g| g.to_string()))
+        .bind(filter.since)
+        .bind(filter.until)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_summaries(&self) -> Result<Vec<DbSummary>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT channel, summary, last_update
+FROM channels"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_period_summaries(&self) -> Result<Vec<DbPeriodSummary>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, channel, level, period_start, period_end, summary, created_at
+FROM period_summaries
+WHERE NOT rolled_up"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<DbUser>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, scope, name, info, last_update
+FROM users"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_user_names(&self) -> Result<Vec<DbSeenName>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT user_id, name, first_seen, last_seen
+FROM user_names"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_user_settings(&self) -> Result<Vec<DbUserSettings>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT id, share_profile
+FROM user_settings"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn import_message(&self, message: &DbMessage) -> Result<bool, sqlx::error::Error> {
+        let result = sqlx::query(
+            r#"
+INSERT INTO messages ( channel, scope, sender_id, sender, message, date_time, message_id, raw_message )
+SELECT $1, $2, $3, $4, $5, $6, $7, $8
+WHERE NOT EXISTS (SELECT 1 FROM messages
+                  WHERE channel = $1 AND date_time = $6 AND sender_id = $3 AND message = $5)"#,
+        )
+        .bind(&message.channel)
+        .bind(&message.scope)
+        .bind(&message.sender_id)
+        .bind(&message.sender)
+        .bind(&message.message)
+        .bind(message.date_time)
+        .bind(&message.message_id)
+        .bind(&message.raw_message)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn import_summary(&self, summary: &DbSummary) -> Result<bool, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"
+INSERT INTO channels (channel, summary, last_update)
+VALUES ($1, $2, $3)
+ON CONFLICT (channel) DO UPDATE
+SET summary = excluded.summary, last_update = excluded.last_update
+WHERE excluded.last_update > channels.last_update"#,
+        )
+        .bind(&summary.channel)
+        .bind(&summary.summary)
+        .bind(summary.last_update)
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        add_summary_revision(&mut tx, summary, RevisionSource::Import, None).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn import_period_summary(
+        &self,
+        summary: &DbPeriodSummary,
+    ) -> Result<bool, sqlx::error::Error> {
+        let result = sqlx::query(
+            r#"
+INSERT INTO period_summaries (channel, level, period_start, period_end, summary, created_at)
+SELECT $1, $2, $3, $4, $5, $6
+WHERE NOT EXISTS (SELECT 1 FROM period_summaries
+                  WHERE channel = $1 AND level = $2 AND period_start = $3 AND period_end = $4)"#,
+        )
+        .bind(&summary.channel)
+        .bind(&summary.level)
+        .bind(summary.period_start)
+        .bind(summary.period_end)
+        .bind(&summary.summary)
+        .bind(summary.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn import_user(&self, user: &DbUser) -> Result<bool, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"
+INSERT INTO users (id, scope, name, info, last_update)
+VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (id, scope) DO UPDATE
+SET name = excluded.name, info = excluded.info, last_update = excluded.last_update
+WHERE excluded.last_update > users.last_update"#,
+        )
+        .bind(&user.id)
+        .bind(&user.scope)
+        .bind(&user.name)
+        .bind(&user.info)
+        .bind(user.last_update)
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        add_user_revision(&mut tx, user, RevisionSource::Import, None).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn import_user_name(&self, name: &DbSeenName) -> Result<bool, sqlx::error::Error> {
+        let result = sqlx::query(
+            r#"
+INSERT INTO user_names (user_id, name, first_seen, last_seen)
+VALUES ($1, $2, $3, $4)
+ON CONFLICT (user_id, name) DO UPDATE
+SET first_seen = LEAST(user_names.first_seen, excluded.first_seen),
+    last_seen = GREATEST(user_names.last_seen, excluded.last_seen)
+WHERE excluded.first_seen < user_names.first_seen OR excluded.last_seen > user_names.last_seen"#,
+        )
+        .bind(&name.user_id)
+        .bind(&name.name)
+        .bind(name.first_seen)
+        .bind(name.last_seen)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_access_rules(&self) -> Result<Vec<DbAccessRule>, sqlx::error::Error> {
+        sqlx::query_as(
+            r#"
+SELECT scope, target, access
+FROM access_rules"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn set_access_rule(
+        &self,
+        scope: &str,
+        target: u64,
+        access: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        sqlx::query(
+            r#"
+INSERT INTO access_rules (scope, target, access)
+VALUES ($1, $2, $3)
+ON CONFLICT (scope, target) DO UPDATE SET access = excluded.access"#,
+        )
+        .bind(scope)
+        .bind(target.to_string())
+        .bind(access)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_access_rule(&self, scope: &str, target: u64) -> Result<(), sqlx::error::Error> {
+        sqlx::query(
+            r#"
+DELETE FROM access_rules
+WHERE scope = $1 AND target = $2"#,
+        )
+        .bind(scope)
+        .bind(target.to_string())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
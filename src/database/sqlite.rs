@@ -0,0 +1,1120 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use sqlx::sqlite::{
+    Sqlite, SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions,
+    SqliteSynchronous,
+};
+use sqlx::Transaction;
+
+use super::{
+    compress_messages, decompress_messages, DbAccessRule, DbMessage, DbPeriodSummary,
+    DbProfileProposal, DbSeenName, DbSummary, DbSummaryRevision, DbUser, DbUserName,
+    DbUserRevision, DbUserSettings, MessageFilter, MessageSearch, ProfileScope, RevisionSource,
+    Storage, SummaryLevel, KASUMI_ID,
+};
+use crate::config::DatabaseConfig;
+
+#[derive(Clone)]
+pub struct SqliteDatabase {
+    pool: SqlitePool,
+}
+
+impl SqliteDatabase {
+    pub async fn new(config: &DatabaseConfig) -> Result<Self, sqlx::error::Error> {
+        let options = SqliteConnectOptions::from_str(&config.url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(config.busy_timeout());
+
+        // every connection to an in-memory database gets its own database
+        let max_connections = if config.url.contains(":memory:") {
+            1
+        } else {
+            config.max_connections
+        };
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .min_connections(1)
+            .acquire_timeout(config.busy_timeout())
+            .connect_with(options)
+            .await?;
+        sqlx::migrate!().run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+async fn add_summary_revision(
+    tx: &mut Transaction<'_, Sqlite>,
+    summary: &DbSummary,
+    source: RevisionSource,
+    model_response: Option<&str>,
+) -> Result<(), sqlx::error::Error> {
+    let source = source.to_string();
+    sqlx::query!(
+        r#"
+INSERT INTO summary_revisions (channel, summary, source, model_response, created_at)
+VALUES (?1, ?2, ?3, ?4, ?5)"#,
+        summary.channel,
+        summary.summary,
+        source,
+        model_response,
+        summary.last_update
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+async fn add_user_revision(
+    tx: &mut Transaction<'_, Sqlite>,
+    user: &DbUser,
+    source: RevisionSource,
+    model_response: Option<&str>,
+) -> Result<(), sqlx::error::Error> {
+    let source = source.to_string();
+    sqlx::query!(
+        r#"
+INSERT INTO user_revisions (user_id, scope, name, info, source, model_response, created_at)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+        user.id,
+        user.scope,
+        user.name,
+        user.info,
+        source,
+        model_response,
+        user.last_update
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+async fn set_summary(
+    tx: &mut Transaction<'_, Sqlite>,
+    summary: &DbSummary,
+) -> Result<(), sqlx::error::Error> {
+    sqlx::query!(
+        r#"
+INSERT OR REPLACE INTO channels (channel, summary, last_update)
+VALUES (?1, ?2, ?3);"#,
+        summary.channel,
+        summary.summary,
+        summary.last_update
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+async fn add_period_summary(
+    tx: &mut Transaction<'_, Sqlite>,
+    summary: &DbPeriodSummary,
+) -> Result<i64, sqlx::error::Error> {
+    let result = sqlx::query!(
+        r#"
+INSERT INTO period_summaries (channel, level, period_start, period_end, summary, created_at)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+        summary.channel,
+        summary.level,
+        summary.period_start,
+        summary.period_end,
+        summary.summary,
+        summary.created_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(result.last_insert_rowid())
+}
+
+async fn set_user(
+    tx: &mut Transaction<'_, Sqlite>,
+    user: &DbUser,
+) -> Result<(), sqlx::error::Error> {
+    sqlx::query!(
+        r#"
+INSERT OR REPLACE INTO users (id, scope, name, info, last_update)
+VALUES (?1, ?2, ?3, ?4, ?5);"#,
+        user.id,
+        user.scope,
+        user.name,
+        user.info,
+        user.last_update
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SqliteDatabase {
+    async fn get_messages_by_date(
+        &self,
+        channel: u64,
+        after: NaiveDateTime,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let channel = channel.to_string();
+        let mut messages = sqlx::query_as!(
+            DbMessage,
+            r#"
+SELECT channel as "channel!", scope as "scope!", sender_id as "sender_id!", sender as "sender!",
+message as "message!", date_time as "date_time!", message_id, raw_message
+FROM messages
+WHERE channel = ? AND date_time > ?
+ORDER BY date_time DESC"#,
+            channel,
+            after
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn get_messages_by_count(
+        &self,
+        channel: u64,
+        count: i64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let channel = channel.to_string();
+        let mut messages = sqlx::query_as!(
+            DbMessage,
+            r#"
+SELECT channel as "channel!", scope as "scope!", sender_id as "sender_id!", sender as "sender!",
+message as "message!", date_time as "date_time!", message_id, raw_message
+FROM messages
+WHERE channel = ?
+ORDER BY date_time DESC
+LIMIT ?"#,
+            channel,
+            count
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn add_message(&self, message: &DbMessage) -> Result<(), sqlx::error::Error> {
+        sqlx::query!(
+            r#"
+INSERT INTO messages ( channel, scope, sender_id, sender, message, date_time, message_id, raw_message )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8 )"#,
+            message.channel,
+            message.scope,
+            message.sender_id,
+            message.sender,
+            message.message,
+            message.date_time,
+            message.message_id,
+            message.raw_message
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn search_messages(
+        &self,
+        search: &MessageSearch,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let terms = search.terms();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        // quoted prefix queries, so nothing in the text is taken as FTS5 syntax
+        let query = terms
+            .iter()
+            .map(|term| format!("\"{}\"*", term))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let channel = search.filter.channel.map(|c| c.to_string());
+        let guild = search.filter.guild.map(|g| g.to_string());
+
+        sqlx::query_as!(
+            DbMessage,
+            r#"
+SELECT messages.channel as "channel!", messages.scope as "scope!",
+messages.sender_id as "sender_id!", messages.sender as "sender!",
+messages.message as "message!", messages.date_time as "date_time!", messages.message_id, messages.raw_message
+FROM messages_fts
+JOIN messages ON messages.id = messages_fts.rowid
+WHERE messages_fts MATCH ?1
+AND (?2 IS NULL OR messages.channel = ?2)
+AND (?3 IS NULL OR messages.scope = ?3)
+AND (?4 IS NULL OR messages.sender_id = ?4)
+AND (?5 IS NULL OR messages.date_time >= ?5)
+AND (?6 IS NULL OR messages.date_time < ?6)
+ORDER BY messages_fts.rank, messages.date_time DESC
+LIMIT ?7"#,
+            query,
+            channel,
+            guild,
+            search.sender_id,
+            search.filter.since,
+            search.filter.until,
+            search.limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_users(
+        &self,
+        scope: ProfileScope,
+        ids: &[String],
+    ) -> Result<Vec<DbUser>, sqlx::error::Error> {
+        let sql = format!(
+            r#"
+SELECT users.id, users.scope, users.name, users.info, users.last_update
+FROM users
+LEFT JOIN user_settings ON user_settings.id = users.id
+WHERE users.id IN ( {} )
+AND (users.scope = ?
+     OR (users.scope = 'global' AND (users.id = ? OR user_settings.share_profile)))"#,
+            vec!["?"; ids.len()].join(", ")
+        );
+
+        let mut query = sqlx::query_as(&sql);
+        for id in ids {
+            query = query.bind(id);
+        }
+        let users: Vec<DbUser> = query
+            .bind(scope.to_string())
+            .bind(KASUMI_ID)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(users)
+    }
+
+    async fn update_user(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        name: &str,
+        info: &str,
+        source: RevisionSource,
+        model_response: Option<&str>,
+    ) -> Result<(), sqlx::error::Error> {
+        let user = DbUser {
+            id: id.to_string(),
+            scope: scope.to_string(),
+            name: name.to_string(),
+            info: info.to_string(),
+            last_update: Utc::now().naive_utc(),
+        };
+
+        let mut tx = self.pool.begin().await?;
+        set_user(&mut tx, &user).await?;
+        add_user_revision(&mut tx, &user, source, model_response).await?;
+        tx.commit().await
+    }
+
+    async fn shares_profile(&self, id: &str) -> Result<bool, sqlx::error::Error> {
+        let shared = sqlx::query_scalar!(
+            r#"
+SELECT share_profile as "share_profile: bool"
+FROM user_settings WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(shared.unwrap_or(false))
+    }
+
+    async fn set_share_profile(&self, id: &str, share: bool) -> Result<(), sqlx::error::Error> {
+        sqlx::query!(
+            r#"
+INSERT INTO user_settings (id, share_profile)
+VALUES (?1, ?2)
+ON CONFLICT (id) DO UPDATE SET share_profile = excluded.share_profile;"#,
+            id,
+            share
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn touch_user(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        username: &str,
+        display_name: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        let now = Utc::now().naive_utc();
+        let legacy_id = format!("legacy:{}", username);
+        let scope_key = scope.to_string();
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+INSERT INTO user_names (user_id, name, first_seen, last_seen)
+VALUES (?1, ?2, ?3, ?3)
+ON CONFLICT (user_id, name) DO UPDATE SET last_seen = excluded.last_seen;"#,
+            id,
+            display_name,
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+UPDATE users SET id = ?1
+WHERE id = ?2
+AND NOT EXISTS (SELECT 1 FROM users AS other WHERE other.id = ?1 AND other.scope = users.scope);"#,
+            id,
+            legacy_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+UPDATE user_revisions SET user_id = ?1
+WHERE user_id = ?2
+AND NOT EXISTS (SELECT 1 FROM users WHERE users.id = ?2 AND users.scope = user_revisions.scope);"#,
+            id,
+            legacy_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+UPDATE messages SET sender_id = ?1
+WHERE sender_id = ?2;"#,
+            id,
+            legacy_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        if let ProfileScope::Guild(_) = scope {
+            sqlx::query!(
+                r#"
+UPDATE users SET scope = ?2
+WHERE id = ?1 AND scope = 'legacy'
+AND NOT EXISTS (SELECT 1 FROM users AS other WHERE other.id = ?1 AND other.scope = ?2);"#,
+                id,
+                scope_key
+            )
+            .execute(&mut tx)
+            .await?;
+
+            sqlx::query!(
+                r#"
+UPDATE user_revisions SET scope = ?2
+WHERE user_id = ?1 AND scope = 'legacy'
+AND NOT EXISTS (SELECT 1 FROM users WHERE users.id = ?1 AND users.scope = 'legacy');"#,
+                id,
+                scope_key
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        sqlx::query!(
+            r#"
+UPDATE users SET name = ?2
+WHERE id = ?1 AND scope IN (?3, 'global');"#,
+            id,
+            display_name,
+            scope_key
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    async fn get_user_names(&self, ids: &[String]) -> Result<Vec<DbUserName>, sqlx::error::Error> {
+        let sql = format!(
+            r#"
+SELECT user_id, name
+FROM user_names WHERE user_id IN ( {} )
+ORDER BY last_seen DESC"#,
+            vec!["?"; ids.len()].join(", ")
+        );
+
+        let mut query = sqlx::query_as(&sql);
+        for id in ids {
+            query = query.bind(id);
+        }
+        query.fetch_all(&self.pool).await
+    }
+
+    async fn get_user_history(
+        &self,
+        scope: ProfileScope,
+        id: &str,
+        limit: i64,
+    ) -> Result<Vec<DbUserRevision>, sqlx::error::Error> {
+        let scope = scope.to_string();
+        sqlx::query_as!(
+            DbUserRevision,
+            r#"
+SELECT id as "id!", user_id as "user_id!", scope as "scope!", name as "name!", info as "info!",
+source as "source!", model_response, created_at as "created_at!"
+FROM user_revisions
+WHERE user_id = ? AND scope = ?
+ORDER BY id DESC
+LIMIT ?"#,
+            id,
+            scope,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_user_revision(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbUserRevision>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbUserRevision,
+            r#"
+SELECT id as "id!", user_id as "user_id!", scope as "scope!", name as "name!", info as "info!",
+source as "source!", model_response, created_at as "created_at!"
+FROM user_revisions
+WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn rollback_user(&self, id: i64) -> Result<Option<DbUserRevision>, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let Some(revision) = sqlx::query_as!(
+            DbUserRevision,
+            r#"
+SELECT id as "id!", user_id as "user_id!", scope as "scope!", name as "name!", info as "info!",
+source as "source!", model_response, created_at as "created_at!"
+FROM user_revisions
+WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let user = DbUser {
+            id: revision.user_id.clone(),
+            scope: revision.scope.clone(),
+            name: revision.name.clone(),
+            info: revision.info.clone(),
+            last_update: Utc::now().naive_utc(),
+        };
+        set_user(&mut tx, &user).await?;
+        add_user_revision(&mut tx, &user, RevisionSource::Admin, None).await?;
+        tx.commit().await?;
+        Ok(Some(revision))
+    }
+
+    async fn add_profile_proposal(
+        &self,
+        proposal: &DbProfileProposal,
+    ) -> Result<i64, sqlx::error::Error> {
+        let result = sqlx::query!(
+            r#"
+INSERT INTO profile_proposals (user_id, scope, name, changes, model_response, created_at)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+            proposal.user_id,
+            proposal.scope,
+            proposal.name,
+            proposal.changes,
+            proposal.model_response,
+            proposal.created_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn get_profile_proposals(
+        &self,
+        user_id: Option<&str>,
+    ) -> Result<Vec<DbProfileProposal>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbProfileProposal,
+            r#"
+SELECT id as "id!", user_id as "user_id!", scope as "scope!", name as "name!",
+changes as "changes!", model_response, created_at as "created_at!"
+FROM profile_proposals
+WHERE (?1 IS NULL OR user_id = ?1)
+ORDER BY id"#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_profile_proposal(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbProfileProposal>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbProfileProposal,
+            r#"
+SELECT id as "id!", user_id as "user_id!", scope as "scope!", name as "name!",
+changes as "changes!", model_response, created_at as "created_at!"
+FROM profile_proposals
+WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn delete_profile_proposal(&self, id: i64) -> Result<bool, sqlx::error::Error> {
+        let result = sqlx::query!(
+            r#"
+DELETE FROM profile_proposals
+WHERE id = ?"#,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_summary(&self, channel: u64) -> Result<Option<DbSummary>, sqlx::error::Error> {
+        let channel = channel.to_string();
+
+        sqlx::query_as!(
+            DbSummary,
+            r#"
+SELECT channel as "channel!", summary as "summary!", last_update as "last_update!"
+FROM channels WHERE channel = ?"#,
+            channel
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn update_summary(
+        &self,
+        channel: u64,
+        summary: &str,
+        source: RevisionSource,
+        model_response: Option<&str>,
+        session_start: Option<NaiveDateTime>,
+    ) -> Result<(), sqlx::error::Error> {
+        let summary = DbSummary {
+            channel: channel.to_string(),
+            summary: summary.to_string(),
+            last_update: Utc::now().naive_utc(),
+        };
+
+        let mut tx = self.pool.begin().await?;
+        set_summary(&mut tx, &summary).await?;
+        add_summary_revision(&mut tx, &summary, source, model_response).await?;
+        if let Some(period_start) = session_start {
+            let session = DbPeriodSummary {
+                id: 0,
+                channel: summary.channel.clone(),
+                level: SummaryLevel::Session.to_string(),
+                period_start,
+                period_end: summary.last_update,
+                summary: summary.summary.clone(),
+                created_at: summary.last_update,
+            };
+            add_period_summary(&mut tx, &session).await?;
+        }
+        tx.commit().await
+    }
+
+    async fn get_period_summaries(
+        &self,
+        channel: u64,
+    ) -> Result<Vec<DbPeriodSummary>, sqlx::error::Error> {
+        let channel = channel.to_string();
+        sqlx::query_as!(
+            DbPeriodSummary,
+            r#"
+SELECT id as "id!", channel as "channel!", level as "level!", period_start as "period_start!",
+period_end as "period_end!", summary as "summary!", created_at as "created_at!"
+FROM period_summaries
+WHERE channel = ? AND rolled_up = FALSE
+ORDER BY period_start, id"#,
+            channel
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn roll_up_summaries(
+        &self,
+        parts: &[i64],
+        summary: &DbPeriodSummary,
+    ) -> Result<i64, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let id = add_period_summary(&mut tx, summary).await?;
+        for part in parts {
+            sqlx::query!(
+                r#"
+UPDATE period_summaries
+SET rolled_up = TRUE
+WHERE id = ?"#,
+                part
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(id)
+    }
+
+    async fn get_summary_history(
+        &self,
+        channel: u64,
+        limit: i64,
+    ) -> Result<Vec<DbSummaryRevision>, sqlx::error::Error> {
+        let channel = channel.to_string();
+        sqlx::query_as!(
+            DbSummaryRevision,
+            r#"
+SELECT id as "id!", channel as "channel!", summary as "summary!", source as "source!",
+model_response, created_at as "created_at!"
+FROM summary_revisions
+WHERE channel = ?
+ORDER BY id DESC
+LIMIT ?"#,
+            channel,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_summary_revision(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbSummaryRevision>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbSummaryRevision,
+            r#"
+SELECT id as "id!", channel as "channel!", summary as "summary!", source as "source!",
+model_response, created_at as "created_at!"
+FROM summary_revisions
+WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn rollback_summary(
+        &self,
+        id: i64,
+    ) -> Result<Option<DbSummaryRevision>, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let Some(revision) = sqlx::query_as!(
+            DbSummaryRevision,
+            r#"
+SELECT id as "id!", channel as "channel!", summary as "summary!", source as "source!",
+model_response, created_at as "created_at!"
+FROM summary_revisions
+WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let summary = DbSummary {
+            channel: revision.channel.clone(),
+            summary: revision.summary.clone(),
+            last_update: revision.created_at,
+        };
+        set_summary(&mut tx, &summary).await?;
+        let session = SummaryLevel::Session.to_string();
+        sqlx::query!(
+            r#"
+DELETE FROM period_summaries
+WHERE channel = ?1 AND level = ?2 AND rolled_up = FALSE AND period_end > ?3"#,
+            summary.channel,
+            session,
+            summary.last_update
+        )
+        .execute(&mut tx)
+        .await?;
+        let restored = DbSummary {
+            last_update: Utc::now().naive_utc(),
+            ..summary
+        };
+        add_summary_revision(&mut tx, &restored, RevisionSource::Admin, None).await?;
+        tx.commit().await?;
+        Ok(Some(revision))
+    }
+
+    async fn channel_list(&self) -> Result<Vec<u64>, sqlx::error::Error> {
+        struct Channel {
+            channel: String,
+        }
+        let channels = sqlx::query_as!(
+            Channel,
+            r#"
+SELECT DISTINCT channel
+FROM messages"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(channels
+            .iter()
+            .filter_map(|s| s.channel.parse().ok())
+            .collect())
+    }
+
+    async fn archive_messages(
+        &self,
+        channel: u64,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::error::Error> {
+        let channel = channel.to_string();
+        let mut tx = self.pool.begin().await?;
+        let messages = sqlx::query_as!(
+            DbMessage,
+            r#"
+SELECT channel as "channel!", scope as "scope!", sender_id as "sender_id!", sender as "sender!",
+message as "message!", date_time as "date_time!", message_id, raw_message
+FROM messages
+WHERE channel = ? AND date_time < ?
+ORDER BY date_time"#,
+            channel,
+            before
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        let (Some(first), Some(last)) = (messages.first(), messages.last()) else {
+            return Ok(0);
+        };
+
+        let data = compress_messages(&messages)?;
+        let count = messages.len() as i64;
+        let now = Utc::now().naive_utc();
+        sqlx::query!(
+            r#"
+INSERT INTO message_archive (channel, first_date_time, last_date_time, message_count, data, archived_at)
+VALUES (?, ?, ?, ?, ?, ?)"#,
+            channel,
+            first.date_time,
+            last.date_time,
+            count,
+            data,
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let deleted = sqlx::query!(
+            r#"
+DELETE FROM messages
+WHERE channel = ? AND date_time < ?"#,
+            channel,
+            before
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn delete_messages(
+        &self,
+        channel: u64,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::error::Error> {
+        let channel = channel.to_string();
+        let result = sqlx::query!(
+            r#"
+DELETE FROM messages
+WHERE channel = ? AND date_time < ?"#,
+            channel,
+            before
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_archived_messages(
+        &self,
+        channel: u64,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let channel = channel.to_string();
+        let batches = sqlx::query!(
+            r#"
+SELECT data
+FROM message_archive
+WHERE channel = ?
+ORDER BY first_date_time"#,
+            channel
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for batch in batches {
+            messages.extend(decompress_messages(&batch.data)?);
+        }
+        Ok(messages)
+    }
+
+    async fn get_all_messages(
+        &self,
+        filter: &MessageFilter,
+    ) -> Result<Vec<DbMessage>, sqlx::error::Error> {
+        let channel = filter.channel.map(|c| c.to_string());
+        let guild = filter.guild.map(|g| g.to_string());
+        sqlx::query_as!(
+            DbMessage,
+            r#"
+SELECT channel as "channel!", scope as "scope!", sender_id as "sender_id!", sender as "sender!",
+message as "message!", date_time as "date_time!", message_id, raw_message
+FROM messages
+WHERE (?1 IS NULL OR channel = ?1)
+AND (?2 IS NULL OR scope = ?2)
+AND (?3 IS NULL OR date_time >= ?3)
+AND (?4 IS NULL OR date_time < ?4)
+ORDER BY date_time"#,
+            channel,
+            guild,
+            filter.since,
+            filter.until
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_summaries(&self) -> Result<Vec<DbSummary>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbSummary,
+            r#"
+SELECT channel as "channel!", summary as "summary!", last_update as "last_update!"
+FROM channels"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_period_summaries(&self) -> Result<Vec<DbPeriodSummary>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbPeriodSummary,
+            r#"
+SELECT id as "id!", channel as "channel!", level as "level!", period_start as "period_start!",
+period_end as "period_end!", summary as "summary!", created_at as "created_at!"
+FROM period_summaries
+WHERE rolled_up = FALSE"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<DbUser>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbUser,
+            r#"
+SELECT id as "id!", scope as "scope!", name as "name!", info as "info!",
+last_update as "last_update!"
+FROM users"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_user_names(&self) -> Result<Vec<DbSeenName>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbSeenName,
+            r#"
+SELECT user_id as "user_id!", name as "name!", first_seen as "first_seen!",
+last_seen as "last_seen!"
+FROM user_names"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_all_user_settings(&self) -> Result<Vec<DbUserSettings>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbUserSettings,
+            r#"
+SELECT id as "id!", share_profile as "share_profile: bool"
+FROM user_settings"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn import_message(&self, message: &DbMessage) -> Result<bool, sqlx::error::Error> {
+        let result = sqlx::query!(
+            r#"
+INSERT INTO messages ( channel, scope, sender_id, sender, message, date_time, message_id, raw_message )
+SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8
+WHERE NOT EXISTS (SELECT 1 FROM messages
+                  WHERE channel = ?1 AND date_time = ?6 AND sender_id = ?3 AND message = ?5)"#,
+            message.channel,
+            message.scope,
+            message.sender_id,
+            message.sender,
+            message.message,
+            message.date_time,
+            message.message_id,
+            message.raw_message
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn import_summary(&self, summary: &DbSummary) -> Result<bool, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query!(
+            r#"
+INSERT INTO channels (channel, summary, last_update)
+VALUES (?1, ?2, ?3)
+ON CONFLICT (channel) DO UPDATE
+SET summary = excluded.summary, last_update = excluded.last_update
+WHERE excluded.last_update > channels.last_update"#,
+            summary.channel,
+            summary.summary,
+            summary.last_update
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        add_summary_revision(&mut tx, summary, RevisionSource::Import, None).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn import_period_summary(
+        &self,
+        summary: &DbPeriodSummary,
+    ) -> Result<bool, sqlx::error::Error> {
+        let result = sqlx::query!(
+            r#"
+INSERT INTO period_summaries (channel, level, period_start, period_end, summary, created_at)
+SELECT ?1, ?2, ?3, ?4, ?5, ?6
+WHERE NOT EXISTS (SELECT 1 FROM period_summaries
+                  WHERE channel = ?1 AND level = ?2 AND period_start = ?3 AND period_end = ?4)"#,
+            summary.channel,
+            summary.level,
+            summary.period_start,
+            summary.period_end,
+            summary.summary,
+            summary.created_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn import_user(&self, user: &DbUser) -> Result<bool, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query!(
+            r#"
+INSERT INTO users (id, scope, name, info, last_update)
+VALUES (?1, ?2, ?3, ?4, ?5)
+ON CONFLICT (id, scope) DO UPDATE
+SET name = excluded.name, info = excluded.info, last_update = excluded.last_update
+WHERE excluded.last_update > users.last_update"#,
+            user.id,
+            user.scope,
+            user.name,
+            user.info,
+            user.last_update
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        add_user_revision(&mut tx, user, RevisionSource::Import, None).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn import_user_name(&self, name: &DbSeenName) -> Result<bool, sqlx::error::Error> {
+        let result = sqlx::query!(
+            r#"
+INSERT INTO user_names (user_id, name, first_seen, last_seen)
+VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT (user_id, name) DO UPDATE
+SET first_seen = MIN(user_names.first_seen, excluded.first_seen),
+    last_seen = MAX(user_names.last_seen, excluded.last_seen)
+WHERE excluded.first_seen < user_names.first_seen OR excluded.last_seen > user_names.last_seen"#,
+            name.user_id,
+            name.name,
+            name.first_seen,
+            name.last_seen
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_access_rules(&self) -> Result<Vec<DbAccessRule>, sqlx::error::Error> {
+        sqlx::query_as!(
+            DbAccessRule,
+            r#"
+SELECT scope, target, access
+FROM access_rules"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn set_access_rule(
+        &self,
+        scope: &str,
+        target: u64,
+        access: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        let target = target.to_string();
+
+        sqlx::query!(
+            r#"
+INSERT OR REPLACE INTO access_rules (scope, target, access)
+VALUES (?1, ?2, ?3);"#,
+            scope,
+            target,
+            access
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_access_rule(&self, scope: &str, target: u64) -> Result<(), sqlx::error::Error> {
+        let target = target.to_string();
+
+        sqlx::query!(
+            r#"
+DELETE FROM access_rules
+WHERE scope = ?1 AND target = ?2;"#,
+            scope,
+            target
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
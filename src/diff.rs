@@ -0,0 +1,66 @@
+/// Marks what changed from `old` to `new` word by word, like `git diff --word-diff`:
+/// removed words as `[-...-]` and added ones as `{+...+}`.
+pub fn word_diff(old: &str, new: &str) -> String {
+    let old = old.split_whitespace().collect::<Vec<_>>();
+    let new = new.split_whitespace().collect::<Vec<_>>();
+
+    // lcs[i][j] is the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut words = Vec::new();
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            flush(&mut words, &mut removed, &mut added);
+            words.push(old[i].to_string());
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            removed.push(old[i]);
+            i += 1;
+        } else {
+            added.push(new[j]);
+            j += 1;
+        }
+    }
+    flush(&mut words, &mut removed, &mut added);
+    words.join(" ")
+}
+
+fn flush<'a>(words: &mut Vec<String>, removed: &mut Vec<&'a str>, added: &mut Vec<&'a str>) {
+    if !removed.is_empty() {
+        words.push(format!("[-{}-]", removed.join(" ")));
+        removed.clear();
+    }
+    if !added.is_empty() {
+        words.push(format!("{{+{}+}}", added.join(" ")));
+        added.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_changed_words() {
+        assert_eq!(
+            word_diff("Bob likes cats and tea.", "Bob likes dogs and tea a lot."),
+            "Bob likes [-cats-] {+dogs+} and [-tea.-] {+tea a lot.+}"
+        );
+        assert_eq!(word_diff("same  text", "same text"), "same text");
+        assert_eq!(word_diff("", "new"), "{+new+}");
+        assert_eq!(word_diff("old words", ""), "[-old words-]");
+    }
+}
@@ -1,12 +0,0 @@
-use std::env;
-
-use once_cell::sync::Lazy;
-
-pub static OPENAI_KEY: Lazy<String> =
-    Lazy::new(|| env::var("OPENAI_KEY").expect("Expected a OPENAI_KEY in the environment"));
-
-pub static DISCORD_TOKEN: Lazy<String> =
-    Lazy::new(|| env::var("DISCORD_TOKEN").expect("Expected a DISCORD_TOKEN in the environment"));
-
-pub static DATABASE_URL: Lazy<String> =
-    Lazy::new(|| env::var("DATABASE_URL").expect("Expected a DATABASE_URL in the environment"));
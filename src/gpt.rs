@@ -1,7 +1,11 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::debug;
 
+use crate::config;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GptRole {
     #[serde(rename = "system")]
@@ -24,6 +28,9 @@ pub enum GptFinishReason {
 pub struct GptMessage {
     pub role: GptRole,
     pub content: String,
+    /// Who wrote a user message, letters, digits, `_` and `-` only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
 #[derive(Clone)]
@@ -48,6 +55,19 @@ pub struct GptError {
     pub code: Option<String>,
 }
 
+impl fmt::Display for GptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}", self.message, self.error_type)?;
+        if let Some(param) = &self.param {
+            write!(f, ", param {}", param)?;
+        }
+        if let Some(code) = &self.code {
+            write!(f, ", code {}", code)?;
+        }
+        f.write_str(")")
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GptUsage {
     pub prompt_tokens: usize,
@@ -65,7 +85,7 @@ struct GptChoice {
 pub enum ChatGPTError {
     #[error("Failed to make request")]
     RequestFailed(#[from] reqwest::Error),
-    #[error("Api returned an error: {0:?}")]
+    #[error("Api returned an error: {0}")]
     RequestError(GptError),
     #[error("Failed to parse response")]
     ParseFailed(#[from] serde_json::Error),
@@ -82,7 +102,7 @@ pub struct GptReply {
 
 #[derive(Debug, Serialize)]
 struct GptRequest<'s> {
-    model: &'static str,
+    model: &'s str,
     messages: &'s [GptMessage],
     temperature: f32,
 }
@@ -100,8 +120,9 @@ impl ChatGPT {
         messages: &[GptMessage],
         temperature: f32,
     ) -> Result<GptReply, ChatGPTError> {
+        let config = config::get();
         let request = GptRequest {
-            model: "gpt-3.5-turbo",
+            model: &config.openai.model,
             messages,
             temperature,
         };
@@ -128,6 +149,10 @@ impl ChatGPT {
                 error: None,
             } => {
                 let choice = choices.pop().ok_or(ChatGPTError::Unknown)?;
+                debug!(
+                    "GPT used {} prompt and {} completion tokens",
+                    usage.prompt_tokens, usage.completion_tokens
+                );
                 Ok(GptReply {
                     message: choice.message,
                     usage,
@@ -1,23 +1,46 @@
-use std::sync::Arc;
+use std::env;
+use std::path::Path;
 
-use regex::{Captures, Regex};
 use serenity::async_trait;
-use serenity::model::channel::Message;
+use serenity::model::channel::{AttachmentType, Channel, ChannelCategory, GuildChannel, Message};
+use serenity::model::event::GuildMemberUpdateEvent;
 use serenity::model::gateway::Ready;
+use serenity::model::guild::{Guild, Member, Role};
+use serenity::model::id::{ChannelId, GuildId, RoleId};
+use serenity::model::user::User;
 use serenity::prelude::*;
 use tracing::{error, info, warn};
 use tracing_appender::non_blocking::WorkerGuard;
 
+use crate::access::{Access, AccessRules};
+use crate::bot::{Author, Reply};
 use crate::channel_typing::TypingManager;
-use crate::database::{Database, DbMessage};
+use crate::database::{Database, DbMessage, ProfileScope};
+use crate::markup::{Attached, Names};
+use crate::name_cache::NameCache;
+use crate::shutdown::Shutdown;
+use crate::split::{split_message, MESSAGE_LIMIT};
 
+mod access;
+mod backup;
 mod bot;
 mod channel_typing;
+mod commands;
+mod config;
 mod database;
-mod envs;
+mod diff;
 mod gpt;
+mod markup;
+mod name_cache;
+mod profile;
 mod prompts;
+mod rate_limit;
+mod retention;
+mod rollup;
+mod shutdown;
+mod split;
 mod summarizer;
+mod templates;
 
 struct BotContainer;
 
@@ -28,7 +51,31 @@ impl TypeMapKey for BotContainer {
 struct TypingContainer;
 
 impl TypeMapKey for TypingContainer {
-    type Value = Arc<Mutex<TypingManager>>;
+    type Value = TypingManager;
+}
+
+struct DatabaseContainer;
+
+impl TypeMapKey for DatabaseContainer {
+    type Value = Database;
+}
+
+struct AccessContainer;
+
+impl TypeMapKey for AccessContainer {
+    type Value = AccessRules;
+}
+
+struct NameCacheContainer;
+
+impl TypeMapKey for NameCacheContainer {
+    type Value = NameCache;
+}
+
+struct ShutdownContainer;
+
+impl TypeMapKey for ShutdownContainer {
+    type Value = Shutdown;
 }
 
 struct Handler;
@@ -36,146 +83,351 @@ struct Handler;
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
-        #[cfg(debug_assertions)]
-        if msg.channel_id.0 != 1085910605799633007 {
+        if msg.author.bot {
             return;
         }
 
-        if msg.author.bot {
+        // keep the bot alive until the reply is sent
+        let in_flight = {
+            let data_read = ctx.data.read().await;
+            data_read
+                .get::<ShutdownContainer>()
+                .expect("Expected ShutdownContainer in TypeMap.")
+                .enter()
+        };
+        let Some(_in_flight) = in_flight else {
+            return;
+        };
+
+        let access_rules = {
+            let data_read = ctx.data.read().await;
+            data_read
+                .get::<AccessContainer>()
+                .expect("Expected AccessContainer in TypeMap.")
+                .clone()
+        };
+        let config = config::get();
+        let name_cache = Self::name_cache(&ctx).await;
+        let with_category = access_rules.uses_categories(&config.access).await;
+        let location = access::locate(&ctx, &msg, &name_cache, with_category).await;
+        let access = access_rules.resolve(&config.access, location).await;
+        if access == Access::Deny {
+            return;
+        }
+        // commands are only answered where Kasumi may talk
+        if commands::is_command(&msg) {
+            if access == Access::Active {
+                commands::handle(&ctx, &msg).await;
+            }
             return;
         }
 
-        let message = Self::replace_ids(&ctx, &msg).await;
+        let names = Names::of(&ctx, &msg, &name_cache).await;
+        let clock = config.time.clock(msg.guild_id.map(|id| id.0));
+        let message = markup::normalize(&msg.content, &names, &Attached::of(&msg), &clock);
         if message.is_empty() {
             return;
         }
 
-        let typing_manager = {
+        let mut bot = {
             let data_read = ctx.data.read().await;
             data_read
-                .get::<TypingContainer>()
-                .expect("Expected TypingContainer in TypeMap.")
+                .get::<BotContainer>()
+                .expect("Expected BotContainer in TypeMap.")
                 .clone()
         };
 
-        {
-            let mut typing_manager = typing_manager.lock().await;
-            typing_manager.start_typing(msg.channel_id.0, ctx.http.clone());
+        let name = msg
+            .member
+            .as_ref()
+            .and_then(|member| member.nick.as_deref())
+            .unwrap_or(&msg.author.name);
+        let author = Author {
+            id: msg.author.id.0,
+            username: &msg.author.name,
+            name,
+        };
+        if let Some(guild_id) = msg.guild_id {
+            name_cache.set_member(guild_id.0, author.id, name);
         }
 
-        let mut bot = {
+        let scope = ProfileScope::of(msg.guild_id.map(|id| id.0));
+
+        if access == Access::ReadOnly {
+            bot.add_message(
+                msg.channel_id.0,
+                msg.id.0,
+                scope,
+                &author,
+                &message,
+                &msg.content,
+            )
+            .await;
+            return;
+        }
+
+        let typing_manager = {
             let data_read = ctx.data.read().await;
             data_read
-                .get::<BotContainer>()
-                .expect("Expected BotContainer in TypeMap.")
+                .get::<TypingContainer>()
+                .expect("Expected TypingContainer in TypeMap.")
                 .clone()
         };
+        let start_typing = || {
+            typing_manager.start(
+                msg.channel_id.0,
+                ctx.http.clone(),
+                config::get().chat.typing_timeout(),
+            )
+        };
 
         if let Some(reply) = bot
-            .process_message(msg.channel_id.0, &msg.author.name, &message)
+            .process_message(
+                msg.channel_id.0,
+                msg.id.0,
+                scope,
+                &author,
+                &message,
+                &msg.content,
+                start_typing,
+            )
             .await
         {
-            if let Err(why) = msg.channel_id.say(&ctx.http, reply).await {
-                warn!("Error sending reply: {:?}", why);
-            }
-        }
-
-        {
-            let mut typing_manager = typing_manager.lock().await;
-            typing_manager.stop_typing(msg.channel_id.0);
+            send_reply(&ctx, msg.channel_id, reply).await;
         }
     }
 
     async fn ready(&self, _: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
     }
+
+    async fn guild_create(&self, ctx: Context, guild: Guild) {
+        Self::name_cache(&ctx).await.add_guild(&guild);
+    }
+
+    async fn guild_member_addition(&self, ctx: Context, member: Member) {
+        Self::name_cache(&ctx).await.add_member(&member);
+    }
+
+    async fn guild_member_update(&self, ctx: Context, update: GuildMemberUpdateEvent) {
+        let name = update.nick.as_deref().unwrap_or(&update.user.name);
+        Self::name_cache(&ctx)
+            .await
+            .set_member(update.guild_id.0, update.user.id.0, name);
+    }
+
+    async fn guild_member_removal(&self, ctx: Context, guild_id: GuildId, user: User) {
+        Self::name_cache(&ctx)
+            .await
+            .remove_member(guild_id.0, user.id.0);
+    }
+
+    async fn guild_role_create(&self, ctx: Context, role: Role) {
+        Self::name_cache(&ctx)
+            .await
+            .set_role(role.guild_id.0, role.id.0, &role.name);
+    }
+
+    async fn guild_role_update(&self, ctx: Context, role: Role) {
+        Self::name_cache(&ctx)
+            .await
+            .set_role(role.guild_id.0, role.id.0, &role.name);
+    }
+
+    async fn guild_role_delete(&self, ctx: Context, guild_id: GuildId, role_id: RoleId) {
+        Self::name_cache(&ctx)
+            .await
+            .remove_role(guild_id.0, role_id.0);
+    }
+
+    async fn channel_create(&self, ctx: Context, channel: &GuildChannel) {
+        Self::name_cache(&ctx).await.add_guild_channel(channel);
+    }
+
+    async fn category_create(&self, ctx: Context, category: &ChannelCategory) {
+        Self::name_cache(&ctx)
+            .await
+            .set_channel(category.id.0, &category.name);
+    }
+
+    async fn channel_update(&self, ctx: Context, channel: Channel) {
+        Self::name_cache(&ctx).await.add_channel(&channel);
+    }
+
+    async fn channel_delete(&self, ctx: Context, channel: &GuildChannel) {
+        Self::name_cache(&ctx).await.remove_channel(channel.id.0);
+    }
+
+    async fn category_delete(&self, ctx: Context, category: &ChannelCategory) {
+        Self::name_cache(&ctx).await.remove_channel(category.id.0);
+    }
 }
 
 impl Handler {
-    // TODO: refactor this
-    async fn replace_ids(ctx: &Context, msg: &Message) -> String {
-        let name_re = Regex::new(r"<@(\d+?)>").unwrap();
-        let users = if let Some(guild_id) = msg.guild_id {
-            guild_id.members(&ctx.http, None, None).await.ok()
-        } else {
-            None
-        };
-        let message = if let Some(users) = users {
-            name_re
-                .replace_all(&msg.content, |m: &Captures| {
-                    users
-                        .iter()
-                        .find(|u| u.user.id.0 == m[1].parse::<u64>().unwrap())
-                        .map(|u| u.user.name.clone())
-                        .unwrap_or_else(|| m[0].to_string())
-                })
-                .to_string()
-        } else {
-            msg.content.trim().to_string()
+    async fn name_cache(ctx: &Context) -> NameCache {
+        let data_read = ctx.data.read().await;
+        data_read
+            .get::<NameCacheContainer>()
+            .expect("Expected NameCacheContainer in TypeMap.")
+            .clone()
+    }
+}
+
+/// Sends a reply in as many messages as it takes, or as a file if it is very long.
+async fn send_reply(ctx: &Context, channel: ChannelId, reply: Reply) {
+    let config = &config::get().chat;
+    let Reply { text, mentions } = reply;
+    if config.attachment_chars > 0 && text.chars().count() > config.attachment_chars {
+        // the file can't ping, so the message mentions the people it addresses
+        let content = mentions
+            .iter()
+            .map(|id| format!("<@{}>", id))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let file = AttachmentType::Bytes {
+            data: text.into_bytes().into(),
+            filename: config.attachment_file.file_name().to_string(),
         };
-        message.trim().to_string()
+        let sent = channel
+            .send_message(&ctx.http, |m| {
+                m.content(content)
+                    .add_file(file)
+                    .allowed_mentions(|a| a.empty_parse().users(mentions))
+            })
+            .await;
+        if let Err(why) = sent {
+            error!("Error sending reply as a file: {:?}", why);
+        }
+        return;
+    }
+    for message in split_message(&text, MESSAGE_LIMIT) {
+        let sent = channel
+            .send_message(&ctx.http, |m| {
+                m.content(message)
+                    .allowed_mentions(|a| a.empty_parse().users(mentions.iter().copied()))
+            })
+            .await;
+        if let Err(why) = sent {
+            error!("Error sending reply: {:?}", why);
+            return;
+        }
     }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // config
+    let config = config::init()?;
+
+    // export and import
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Some(command) = args.first() {
+        let database = Database::new(&config.database).await?;
+        return backup::run(&database, command, &args[1..]).await;
+    }
+    config.check_secrets()?;
+
     // logs
-    let _guard = init_logs();
+    let _guard = init_logs(&config.logs.directory);
+
+    // prompt templates
+    templates::reload();
 
     // load database
-    let database = Database::new().await?;
+    let database = Database::new(&config.database).await?;
+    let access_rules = AccessRules::load(database.clone()).await?;
 
     // create bot
-    let gpt = gpt::ChatGPT::new(&envs::OPENAI_KEY);
-    let bot = bot::Bot::new(database.clone(), gpt.clone());
-
-    // create summarizer
+    let gpt = gpt::ChatGPT::new(&config.openai.key);
     let summarizer = summarizer::Summarizer::new(gpt.clone(), database.clone());
+    let bot = bot::Bot::new(database.clone(), gpt.clone(), summarizer.clone());
+
+    // create background tasks
+    let retention = retention::Retention::new(database.clone());
 
     // create client
-    let intents = GatewayIntents::GUILD_MESSAGES
+    let mut intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT;
-    let token = envs::DISCORD_TOKEN.to_string();
+    if config.discord.member_events {
+        intents |= GatewayIntents::GUILD_MEMBERS;
+    }
+    let token = config.discord.token.clone();
     let mut client = Client::builder(token, intents)
         .event_handler(Handler)
         .await?;
 
     // insert data
+    let shutdown = Shutdown::new();
+    let typing_manager = TypingManager::new();
     {
         let mut data = client.data.write().await;
         data.insert::<BotContainer>(bot);
-        data.insert::<TypingContainer>(Arc::new(Mutex::new(TypingManager::new())));
+        data.insert::<AccessContainer>(access_rules);
+        data.insert::<DatabaseContainer>(database.clone());
+        data.insert::<NameCacheContainer>(NameCache::new(config.discord.name_cache_ttl()));
+        data.insert::<TypingContainer>(typing_manager.clone());
+        data.insert::<ShutdownContainer>(shutdown.clone());
     }
 
-    // Start bot and wait for enter
+    // Start bot and wait for a signal
+    let shard_manager = client.shard_manager.clone();
+    let mut client_task = tokio::spawn(async move { client.start().await });
+    let summarizer_task = {
+        let summarizer = summarizer.clone();
+        tokio::spawn(async move { summarizer.start().await })
+    };
+    let retention_task = tokio::spawn(async move { retention.start().await });
+    let config_task = tokio::spawn(config::watch());
     tokio::select! {
-        _ = client.start()  => {
+        _ = &mut client_task => {
             error!("Client stopped");
         }
-        _ = tokio::task::spawn_blocking(||{
-            wait_enter()
-        })  =>  {
-            info!("User pressed enter");
-        }
-        _ = summarizer.start() => {
-            error!("Summarizer stopped");
+        _ = shutdown::wait_signal() => {
+            info!("Received shutdown signal");
         }
     }
+    summarizer_task.abort();
+    retention_task.abort();
+    config_task.abort();
+
+    // Let pending generations finish and send their replies
+    info!(
+        "Waiting for {} pending messages to finish",
+        shutdown.in_flight()
+    );
+    if !shutdown.drain(config::get().shutdown.grace_period()).await {
+        warn!(
+            "Grace period elapsed with {} messages still pending",
+            shutdown.in_flight()
+        );
+    }
+
+    // Summarize what was said since the last pass, unless the model hangs
+    let grace_period = config::get().shutdown.grace_period();
+    if tokio::time::timeout(grace_period, summarizer.summarize_now())
+        .await
+        .is_err()
+    {
+        warn!("Summarizing took longer than the grace period, stopping without it");
+    }
 
     // Stop the client
-    client.shard_manager.lock().await.shutdown_all().await;
+    typing_manager.stop_all();
+    shard_manager.lock().await.shutdown_all().await;
+    client_task.abort();
     info!("Kasumi stopped");
     Ok(())
 }
 
-fn init_logs() -> WorkerGuard {
+fn init_logs(directory: &Path) -> WorkerGuard {
     use tracing_subscriber::filter;
     use tracing_subscriber::layer::SubscriberExt;
     use tracing_subscriber::util::SubscriberInitExt;
     use tracing_subscriber::Layer;
 
-    let file_appender = tracing_appender::rolling::hourly("logs", "prefix.log");
+    let file_appender = tracing_appender::rolling::hourly(directory, "prefix.log");
     let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
     let file_subscriber = tracing_subscriber::fmt::layer()
         .compact()
@@ -194,10 +446,3 @@ fn init_logs() -> WorkerGuard {
         .init();
     guard
 }
-
-fn wait_enter() {
-    use std::io;
-    let mut user_input = String::new();
-    let stdin = io::stdin(); // We get `Stdin` here.
-    let _ = stdin.read_line(&mut user_input);
-}
@@ -0,0 +1,390 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use chrono::{TimeZone, Utc};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, RoleId};
+use serenity::prelude::*;
+use tracing::warn;
+
+use crate::config::Clock;
+use crate::name_cache::NameCache;
+
+/// Every kind of Discord markup that refers to something by id.
+static MARKUP: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"<@!?(?P<user>\d+)>",
+        r"|<@&(?P<role>\d+)>",
+        r"|<#(?P<channel>\d+)>",
+        r"|<a?:(?P<emoji>\w+):\d+>",
+        r"|<t:(?P<time>-?\d+)(?::(?P<style>[tTdDfFR]))?>",
+        r"|</(?P<command>[\w -]+):\d+>",
+    ))
+    .unwrap()
+});
+
+/// Names of the users, channels and roles a message mentions, by id.
+#[derive(Debug, Default)]
+pub struct Names {
+    pub users: HashMap<u64, String>,
+    pub channels: HashMap<u64, String>,
+    pub roles: HashMap<u64, String>,
+}
+
+/// What a message has besides its text, as it reads in prompts.
+#[derive(Debug, Default)]
+pub struct Attached {
+    pub attachments: Vec<String>,
+    pub stickers: Vec<String>,
+    pub embeds: Vec<String>,
+}
+
+/// The ids of the users, channels and roles mentioned in the text.
+fn mentioned(content: &str) -> (HashSet<u64>, HashSet<u64>, HashSet<u64>) {
+    let mut users = HashSet::new();
+    let mut channels = HashSet::new();
+    let mut roles = HashSet::new();
+    for caps in MARKUP.captures_iter(content) {
+        let (ids, id) = if let Some(id) = caps.name("user") {
+            (&mut users, id)
+        } else if let Some(id) = caps.name("channel") {
+            (&mut channels, id)
+        } else if let Some(id) = caps.name("role") {
+            (&mut roles, id)
+        } else {
+            continue;
+        };
+        if let Ok(id) = id.as_str().parse() {
+            ids.insert(id);
+        }
+    }
+    (users, channels, roles)
+}
+
+/// The text of a message with its markup as readable text, followed by its
+/// attachments, stickers and embeds.
+pub fn normalize(content: &str, names: &Names, attached: &Attached, clock: &Clock) -> String {
+    let text = MARKUP.replace_all(content, |caps: &Captures| {
+        if let Some(id) = caps.name("user") {
+            format!("@{}", name(&names.users, id.as_str(), "unknown-user"))
+        } else if let Some(id) = caps.name("role") {
+            format!("@{}", name(&names.roles, id.as_str(), "unknown-role"))
+        } else if let Some(id) = caps.name("channel") {
+            format!("#{}", name(&names.channels, id.as_str(), "unknown-channel"))
+        } else if let Some(emoji) = caps.name("emoji") {
+            format!(":{}:", emoji.as_str())
+        } else if let Some(time) = caps.name("time") {
+            timestamp(time.as_str(), caps.name("style").map(|s| s.as_str()), clock)
+                .unwrap_or_else(|| caps[0].to_string())
+        } else if let Some(command) = caps.name("command") {
+            format!("/{}", command.as_str())
+        } else {
+            caps[0].to_string()
+        }
+    });
+
+    let mut parts = vec![text.trim().to_string()];
+    parts.extend(attached.attachments.iter().cloned());
+    parts.extend(attached.stickers.iter().cloned());
+    parts.extend(attached.embeds.iter().cloned());
+    parts.retain(|part| !part.is_empty());
+    parts.join(" ")
+}
+
+fn name<'a>(names: &'a HashMap<u64, String>, id: &str, unknown: &'a str) -> &'a str {
+    id.parse()
+        .ok()
+        .and_then(|id| names.get(&id))
+        .map_or(unknown, String::as_str)
+}
+
+/// A `<t:...>` timestamp in the time zone and locale of the server.
+/// Relative ones are shown as dates too, since they are read long after they were sent.
+fn timestamp(seconds: &str, style: Option<&str>, clock: &Clock) -> Option<String> {
+    let time = Utc
+        .timestamp_opt(seconds.parse().ok()?, 0)
+        .single()?
+        .with_timezone(&clock.timezone);
+    let format = match style {
+        Some("t") => "%H:%M",
+        Some("T") => "%H:%M:%S",
+        Some("d") => "%d.%m.%Y",
+        Some("D") => "%-d %B %Y",
+        Some("F") => "%A, %-d %B %Y %H:%M",
+        _ => "%-d %B %Y %H:%M",
+    };
+    Some(time.format_localized(format, clock.locale).to_string())
+}
+
+impl Attached {
+    pub fn of(msg: &Message) -> Self {
+        let attachments = msg
+            .attachments
+            .iter()
+            .map(|a| {
+                let kind = match a.content_type.as_deref() {
+                    Some(t) if t.starts_with("image/") => "image",
+                    Some(t) if t.starts_with("video/") => "video",
+                    Some(t) if t.starts_with("audio/") => "audio",
+                    _ => "file",
+                };
+                format!("[{}: {}]", kind, a.filename)
+            })
+            .collect();
+        let stickers = msg
+            .sticker_items
+            .iter()
+            .map(|s| format!("[sticker: {}]", s.name))
+            .collect();
+        let embeds = msg
+            .embeds
+            .iter()
+            .filter_map(|e| match (&e.title, e.kind.as_deref()) {
+                (Some(title), _) => Some(format!("[link: {}]", title)),
+                (None, Some(kind @ ("image" | "gifv" | "video"))) => Some(format!("[{}]", kind)),
+                _ => None,
+            })
+            .collect();
+        Self {
+            attachments,
+            stickers,
+            embeds,
+        }
+    }
+}
+
+impl Names {
+    /// Looks up the names of what the message mentions, asking Discord only about
+    /// what the cache doesn't know.
+    pub async fn of(ctx: &Context, msg: &Message, cache: &NameCache) -> Self {
+        let (user_ids, channel_ids, role_ids) = mentioned(&msg.content);
+        let mut names = Self::default();
+
+        for id in user_ids {
+            let name = match msg.guild_id {
+                Some(guild_id) => match cache.member(guild_id.0, id) {
+                    Some(name) => Some(name),
+                    None => match guild_id.member(&ctx.http, id).await {
+                        Ok(member) => {
+                            cache.add_member(&member);
+                            Some(member.display_name().into_owned())
+                        }
+                        Err(e) => {
+                            warn!("Failed to get member {} of guild {}: {:?}", id, guild_id, e);
+                            None
+                        }
+                    },
+                },
+                None => None,
+            };
+            // people who left or are in DMs go by their username
+            let name = name.or_else(|| {
+                msg.mentions
+                    .iter()
+                    .find(|u| u.id.0 == id)
+                    .map(|u| u.name.clone())
+            });
+            if let Some(name) = name {
+                names.users.insert(id, name);
+            }
+        }
+
+        for channel in &msg.mention_channels {
+            names.channels.insert(channel.id.0, channel.name.clone());
+        }
+        for id in channel_ids {
+            if names.channels.contains_key(&id) {
+                continue;
+            }
+            if let Some(name) = cache.channel(id) {
+                names.channels.insert(id, name);
+                continue;
+            }
+            match ChannelId(id).to_channel(&ctx.http).await {
+                Ok(channel) => {
+                    cache.add_channel(&channel);
+                    if let Some(name) = cache.channel(id) {
+                        names.channels.insert(id, name);
+                    }
+                }
+                Err(e) => warn!("Failed to get channel {}: {:?}", id, e),
+            }
+        }
+
+        if let Some(guild_id) = msg.guild_id {
+            for id in role_ids {
+                let name = match cache.role(guild_id.0, id) {
+                    Some(name) => name,
+                    None => match guild_id.roles(&ctx.http).await {
+                        Ok(roles) => {
+                            cache.set_roles(
+                                guild_id.0,
+                                roles.values().map(|r| (r.id.0, r.name.as_str())),
+                            );
+                            roles.get(&RoleId(id)).map(|r| r.name.clone())
+                        }
+                        Err(e) => {
+                            warn!("Failed to get roles of guild {}: {:?}", guild_id, e);
+                            None
+                        }
+                    },
+                };
+                if let Some(name) = name {
+                    names.roles.insert(id, name);
+                }
+            }
+        }
+        names
+    }
+}
+
+/// Pings of everyone, or of a role, in a reply.
+static MASS_PINGS: Lazy<Regex> = Lazy::new(|| Regex::new(r"@(everyone|here)|<@&\d+>").unwrap());
+
+/// Turns the people a reply addresses into mentions: `@Name` outside of words, or the name
+/// in front of the reply, like "Name, ...". Only the people in `known`, by id and the
+/// name prompts used for them, are mentioned. Returns the reply for Discord with the
+/// ids it mentions.
+pub fn mention_users(text: &str, known: &[(u64, &str)]) -> (String, Vec<u64>) {
+    let mut known = known
+        .iter()
+        .filter(|(_, name)| !name.is_empty())
+        .collect::<Vec<_>>();
+    // "Bob #2" before "Bob"
+    known.sort_by_key(|(_, name)| std::cmp::Reverse(name.len()));
+    let name_at = |start: usize| {
+        known.iter().find(|(_, name)| {
+            let end = start + name.len();
+            text.get(start..end)
+                .is_some_and(|s| s.to_lowercase() == name.to_lowercase())
+                && !text[end..].starts_with(|c: char| c.is_alphanumeric())
+        })
+    };
+
+    // where the mentions go, as (start, end, id)
+    let mut spans = Vec::new();
+    if let Some((id, name)) = name_at(0) {
+        if text[name.len()..].starts_with([',', ':', '!']) {
+            spans.push((0, name.len(), *id));
+        }
+    }
+    for (at, _) in text.match_indices('@') {
+        // not inside a word, like an email address
+        let in_word = text[..at]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        if in_word {
+            continue;
+        }
+        if let Some((id, name)) = name_at(at + 1) {
+            spans.push((at, at + 1 + name.len(), *id));
+        }
+    }
+
+    let mut reply = String::new();
+    let mut ids = Vec::new();
+    let mut rest = 0;
+    for (start, end, id) in spans {
+        reply.push_str(&text[rest..start]);
+        reply.push_str(&format!("<@{}>", id));
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+        rest = end;
+    }
+    reply.push_str(&text[rest..]);
+    (reply, ids)
+}
+
+/// Keeps a reply from pinging everyone, everyone online or a role.
+pub fn neutralize_pings(text: &str) -> Cow<'_, str> {
+    MASS_PINGS.replace_all(text, |caps: &Captures| match caps.get(1) {
+        Some(everyone) => format!("@\u{200b}{}", everyone.as_str()),
+        None => "@role".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TimeConfig;
+
+    #[test]
+    fn markup_reads_as_text() {
+        let names = Names {
+            users: HashMap::from([(1, "Bob".to_string())]),
+            channels: HashMap::from([(2, "general".to_string())]),
+            roles: HashMap::from([(3, "Mods".to_string())]),
+        };
+        let clock = TimeConfig::default().clock(None);
+        let text = "<@1> <@!1> <@9> in <#2> ask <@&3> <:kek:123> <a:dance:456> \
+                    at <t:1682370000:f> or <t:1682370000:t>, try </help:789>";
+        assert_eq!(
+            normalize(text, &names, &Attached::default(), &clock),
+            "@Bob @Bob @unknown-user in #general ask @Mods :kek: :dance: \
+             at 24 April 2023 21:00 or 21:00, try /help"
+        );
+    }
+
+    #[test]
+    fn attachments_follow_the_text() {
+        let attached = Attached {
+            attachments: vec!["[image: cat.png]".to_string()],
+            stickers: vec!["[sticker: wave]".to_string()],
+            embeds: vec!["[link: Example Domain]".to_string()],
+        };
+        let clock = TimeConfig::default().clock(None);
+        assert_eq!(
+            normalize("  look ", &Names::default(), &attached, &clock),
+            "look [image: cat.png] [sticker: wave] [link: Example Domain]"
+        );
+        assert_eq!(
+            normalize("", &Names::default(), &attached, &clock),
+            "[image: cat.png] [sticker: wave] [link: Example Domain]"
+        );
+        assert_eq!(
+            mentioned("<@1> <@!2> <#3> <@&4> <@&4>"),
+            (
+                HashSet::from([1, 2]),
+                HashSet::from([3]),
+                HashSet::from([4])
+            )
+        );
+    }
+
+    #[test]
+    fn replies_mention_who_they_address() {
+        let known = [(1, "Norne"), (2, "Bob"), (3, "Bob #2"), (4, "Кася")];
+        let mention = |text| mention_users(text, &known);
+        assert_eq!(
+            mention("Norne, hi! @bob #2 and @Bob, meet @Кася"),
+            (
+                "<@1>, hi! <@3> and <@2>, meet <@4>".to_string(),
+                vec![1, 3, 2, 4]
+            )
+        );
+        assert_eq!(
+            mention("Norne is nice, @Bobby and @Eve aren't, Bob"),
+            (
+                "Norne is nice, @Bobby and @Eve aren't, Bob".to_string(),
+                vec![]
+            )
+        );
+        assert_eq!(mention("@Bob @Bob"), ("<@2> <@2>".to_string(), vec![2]));
+        assert_eq!(
+            mention("Write to bob@Bob.com (@Bob)"),
+            ("Write to bob@Bob.com (<@2>)".to_string(), vec![2])
+        );
+    }
+
+    #[test]
+    fn mass_pings_are_neutralized() {
+        assert_eq!(
+            neutralize_pings("@everyone @here <@&123> <@1> me@here.com"),
+            "@\u{200b}everyone @\u{200b}here @role <@1> me@\u{200b}here.com"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serenity::model::channel::{Channel, GuildChannel};
+use serenity::model::guild::{Guild, Member};
+
+/// Entries kept of each kind before stale ones are dropped.
+const MAX_ENTRIES: usize = 10_000;
+
+struct Entry<V> {
+    value: V,
+    stored: Instant,
+}
+
+struct Names<K, V = String> {
+    entries: HashMap<K, Entry<V>>,
+}
+
+impl<K: Eq + Hash, V: Clone> Names<K, V> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&self, key: &K, ttl: Duration) -> Option<V> {
+        self.entries
+            .get(key)
+            .filter(|e| e.stored.elapsed() < ttl)
+            .map(|e| e.value.clone())
+    }
+
+    fn set(&mut self, key: K, value: V, ttl: Duration) {
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.retain(|_, e| e.stored.elapsed() < ttl);
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                stored: Instant::now(),
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+}
+
+struct State {
+    /// Display names by guild and user.
+    members: Names<(u64, u64)>,
+    channels: Names<u64>,
+    /// The category of each guild channel, `None` for channels outside of one.
+    categories: Names<u64, Option<u64>>,
+    /// Role names by guild and role.
+    roles: Names<(u64, u64)>,
+    /// When all roles of a guild were last stored.
+    guild_roles: HashMap<u64, Instant>,
+}
+
+/// Names of members, channels and roles, and the categories of channels, kept up to date
+/// by gateway events so mentions and access rules resolve without asking Discord. Entries older than the TTL are looked up again.
+#[derive(Clone)]
+pub struct NameCache {
+    state: Arc<Mutex<State>>,
+    ttl: Duration,
+}
+
+impl NameCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                members: Names::new(),
+                channels: Names::new(),
+                categories: Names::new(),
+                roles: Names::new(),
+                guild_roles: HashMap::new(),
+            })),
+            ttl,
+        }
+    }
+
+    pub fn member(&self, guild: u64, user: u64) -> Option<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .members
+            .get(&(guild, user), self.ttl)
+    }
+
+    pub fn set_member(&self, guild: u64, user: u64, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.members.set((guild, user), name.to_string(), self.ttl);
+    }
+
+    pub fn add_member(&self, member: &Member) {
+        self.set_member(member.guild_id.0, member.user.id.0, &member.display_name());
+    }
+
+    pub fn remove_member(&self, guild: u64, user: u64) {
+        self.state.lock().unwrap().members.remove(&(guild, user));
+    }
+
+    pub fn channel(&self, id: u64) -> Option<String> {
+        self.state.lock().unwrap().channels.get(&id, self.ttl)
+    }
+
+    pub fn set_channel(&self, id: u64, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.channels.set(id, name.to_string(), self.ttl);
+    }
+
+    /// The category of a guild channel, `Some(None)` if it is in none.
+    pub fn category(&self, channel: u64) -> Option<Option<u64>> {
+        self.state
+            .lock()
+            .unwrap()
+            .categories
+            .get(&channel, self.ttl)
+    }
+
+    pub fn set_category(&self, channel: u64, category: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.categories.set(channel, category, self.ttl);
+    }
+
+    /// Stores the name and category of a guild channel.
+    pub fn add_guild_channel(&self, channel: &GuildChannel) {
+        self.set_channel(channel.id.0, &channel.name);
+        self.set_category(channel.id.0, channel.parent_id.map(|id| id.0));
+    }
+
+    /// Stores the name of a guild channel or category, and the category of a channel.
+    pub fn add_channel(&self, channel: &Channel) {
+        match channel {
+            Channel::Guild(channel) => self.add_guild_channel(channel),
+            Channel::Category(category) => self.set_channel(category.id.0, &category.name),
+            _ => {}
+        }
+    }
+
+    pub fn remove_channel(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.channels.remove(&id);
+        state.categories.remove(&id);
+    }
+
+    /// The name of a role, or `None` if the roles of the guild have to be looked up.
+    /// A role missing from up to date roles was deleted and is `Some(None)`.
+    pub fn role(&self, guild: u64, id: u64) -> Option<Option<String>> {
+        let state = self.state.lock().unwrap();
+        let name = state.roles.get(&(guild, id), self.ttl);
+        let complete = state
+            .guild_roles
+            .get(&guild)
+            .is_some_and(|stored| stored.elapsed() < self.ttl);
+        match name {
+            Some(name) => Some(Some(name)),
+            None if complete => Some(None),
+            None => None,
+        }
+    }
+
+    pub fn set_role(&self, guild: u64, id: u64, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.roles.set((guild, id), name.to_string(), self.ttl);
+    }
+
+    /// Stores all roles of a guild.
+    pub fn set_roles<'a>(&self, guild: u64, roles: impl IntoIterator<Item = (u64, &'a str)>) {
+        let mut state = self.state.lock().unwrap();
+        for (id, name) in roles {
+            state.roles.set((guild, id), name.to_string(), self.ttl);
+        }
+        state.guild_roles.insert(guild, Instant::now());
+    }
+
+    pub fn remove_role(&self, guild: u64, id: u64) {
+        self.state.lock().unwrap().roles.remove(&(guild, id));
+    }
+
+    /// Stores the channels, roles and members Discord sent with a guild.
+    pub fn add_guild(&self, guild: &Guild) {
+        for channel in guild.channels.values() {
+            self.add_channel(channel);
+        }
+        self.set_roles(
+            guild.id.0,
+            guild.roles.values().map(|r| (r.id.0, r.name.as_str())),
+        );
+        for member in guild.members.values() {
+            self.add_member(member);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_expire_after_the_ttl() {
+        let cache = NameCache::new(Duration::from_secs(60));
+        cache.set_member(1, 2, "Bob");
+        cache.set_channel(3, "general");
+        assert_eq!(cache.member(1, 2).as_deref(), Some("Bob"));
+        assert_eq!(cache.member(9, 2), None);
+        assert_eq!(cache.channel(3).as_deref(), Some("general"));
+        cache.remove_member(1, 2);
+        assert_eq!(cache.member(1, 2), None);
+
+        cache.set_category(3, Some(4));
+        cache.set_category(5, None);
+        assert_eq!(cache.category(3), Some(Some(4)));
+        assert_eq!(cache.category(5), Some(None));
+        cache.remove_channel(3);
+        assert_eq!(cache.category(3), None);
+        assert_eq!(cache.channel(3), None);
+
+        let expired = NameCache::new(Duration::ZERO);
+        expired.set_member(1, 2, "Bob");
+        assert_eq!(expired.member(1, 2), None);
+    }
+
+    #[test]
+    fn roles_are_known_per_guild() {
+        let cache = NameCache::new(Duration::from_secs(60));
+        cache.set_role(1, 10, "Mods");
+        assert_eq!(cache.role(1, 10), Some(Some("Mods".to_string())));
+        // other roles of the guild may exist
+        assert_eq!(cache.role(1, 11), None);
+
+        cache.set_roles(1, [(10, "Mods"), (12, "Admins")]);
+        assert_eq!(cache.role(1, 12), Some(Some("Admins".to_string())));
+        cache.remove_role(1, 12);
+        assert_eq!(cache.role(1, 12), Some(None));
+        assert_eq!(cache.role(2, 12), None);
+    }
+}
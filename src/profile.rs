@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use itertools::Itertools;
+use serde_json::{Map, Value};
+
+use crate::database::{DbProfileProposal, DbUser, ProfileScope, RevisionSource};
+use crate::Database;
+
+/// A part of a profile the summarizer fills in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Field {
+    Name,
+    Age,
+    Job,
+    Likes,
+    Dislikes,
+    Notes,
+}
+
+impl Field {
+    pub const ALL: [Field; 6] = [
+        Field::Name,
+        Field::Age,
+        Field::Job,
+        Field::Likes,
+        Field::Dislikes,
+        Field::Notes,
+    ];
+
+    fn key(self) -> &'static str {
+        match self {
+            Field::Name => "name",
+            Field::Age => "age",
+            Field::Job => "job",
+            Field::Likes => "likes",
+            Field::Dislikes => "dislikes",
+            Field::Notes => "notes",
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.key())
+    }
+}
+
+impl FromStr for Field {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Field::ALL
+            .into_iter()
+            .find(|field| field.key() == s)
+            .ok_or(())
+    }
+}
+
+/// Field values, as proposed by the summarizer or set by hand.
+pub type Changes = BTreeMap<Field, String>;
+
+/// What Kasumi knows about a user, stored as a JSON object in `users.info`:
+/// the fields plus a `locked` list of the ones automation must not touch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub fields: Changes,
+    pub locked: BTreeSet<Field>,
+}
+
+impl Profile {
+    /// Reads a stored profile. Free text from before profiles had fields becomes the notes.
+    pub fn parse(info: &str) -> Self {
+        let Ok(Value::Object(object)) = serde_json::from_str::<Value>(info) else {
+            return Self {
+                fields: notes(info),
+                locked: BTreeSet::new(),
+            };
+        };
+        let locked = match object.get("locked") {
+            Some(Value::Array(locked)) => locked
+                .iter()
+                .filter_map(|field| field.as_str()?.parse().ok())
+                .collect(),
+            _ => BTreeSet::new(),
+        };
+        let mut fields = fields(&object);
+        fields.retain(|_, value| !value.is_empty());
+        Self { fields, locked }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut object = self
+            .fields
+            .iter()
+            .map(|(field, value)| (field.to_string(), Value::from(value.as_str())))
+            .collect::<Map<_, _>>();
+        if !self.locked.is_empty() {
+            let locked = self.locked.iter().map(|f| Value::from(f.key())).collect();
+            object.insert("locked".to_string(), Value::Array(locked));
+        }
+        Value::Object(object).to_string()
+    }
+
+    /// The profile as prompts show it, the notes as they are after the other fields.
+    pub fn render(&self) -> String {
+        let fields = self
+            .fields
+            .iter()
+            .filter(|(field, _)| **field != Field::Notes)
+            .map(|(field, value)| format!("{}: {}", field, value))
+            .join("; ");
+        match self.fields.get(&Field::Notes) {
+            Some(notes) if fields.is_empty() => notes.clone(),
+            Some(notes) => format!("{}. {}", fields, notes),
+            None => fields,
+        }
+    }
+
+    /// Applies the changes to the fields that are not locked; an empty value clears a field.
+    /// Returns the fields that changed.
+    pub fn apply(&mut self, changes: &Changes) -> Vec<Field> {
+        let mut changed = Vec::new();
+        for (field, value) in changes {
+            if self.locked.contains(field) {
+                continue;
+            }
+            let value = value.trim();
+            let old = if value.is_empty() {
+                self.fields.remove(field)
+            } else {
+                self.fields.insert(*field, value.to_string())
+            };
+            if old.as_deref().unwrap_or("") != value {
+                changed.push(*field);
+            }
+        }
+        changed
+    }
+
+    /// The changes without the locked fields.
+    pub fn unlocked(&self, changes: &Changes) -> Changes {
+        changes
+            .iter()
+            .filter(|(field, _)| !self.locked.contains(field))
+            .map(|(field, value)| (*field, value.clone()))
+            .collect()
+    }
+}
+
+/// Reads the fields the summarizer proposes, a JSON object of the fields that changed.
+/// Anything else is taken as notes.
+pub fn parse_changes(text: &str) -> Changes {
+    match serde_json::from_str::<Value>(text) {
+        Ok(Value::Object(object)) => fields(&object),
+        _ => notes(text),
+    }
+}
+
+pub fn changes_to_json(changes: &Changes) -> String {
+    Profile {
+        fields: changes.clone(),
+        locked: BTreeSet::new(),
+    }
+    .to_json()
+}
+
+fn fields(object: &Map<String, Value>) -> Changes {
+    object
+        .iter()
+        .filter_map(|(key, value)| {
+            let field = key.parse().ok()?;
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Null => String::new(),
+                // lists of likes and the like
+                Value::Array(values) => values
+                    .iter()
+                    .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+                    .join(", "),
+                value => value.to_string(),
+            };
+            Some((field, value))
+        })
+        .collect()
+}
+
+fn notes(text: &str) -> Changes {
+    let text = text.trim();
+    if text.is_empty() {
+        Changes::new()
+    } else {
+        Changes::from([(Field::Notes, text.to_string())])
+    }
+}
+
+/// The profile of a user kept in exactly this scope.
+pub async fn get_profile(
+    database: &Database,
+    scope: ProfileScope,
+    id: &str,
+) -> Result<Option<DbUser>, sqlx::error::Error> {
+    let scope_key = scope.to_string();
+    Ok(database
+        .get_users(scope, &[id.to_string()])
+        .await?
+        .into_iter()
+        .find(|u| u.scope == scope_key))
+}
+
+/// Applies a reviewed proposal to the current profile, except for fields locked since,
+/// and removes it from the queue. Returns the fields that changed.
+pub async fn approve(
+    database: &Database,
+    proposal: &DbProfileProposal,
+) -> anyhow::Result<Vec<Field>> {
+    let scope = proposal
+        .scope
+        .parse()
+        .map_err(|_| anyhow::anyhow!("unknown profile scope `{}`", proposal.scope))?;
+    let current = get_profile(database, scope, &proposal.user_id).await?;
+    let mut profile = current
+        .as_ref()
+        .map(|user| Profile::parse(&user.info))
+        .unwrap_or_default();
+    let changed = profile.apply(&parse_changes(&proposal.changes));
+    if !changed.is_empty() {
+        let name = current.as_ref().map_or(&proposal.name, |user| &user.name);
+        database
+            .update_user(
+                scope,
+                &proposal.user_id,
+                name,
+                &profile.to_json(),
+                RevisionSource::Summarizer,
+                proposal.model_response.as_deref(),
+            )
+            .await?;
+    }
+    database.delete_profile_proposal(proposal.id).await?;
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_text_becomes_notes() {
+        let profile = Profile::parse("Likes cats.");
+        assert_eq!(profile.render(), "Likes cats.");
+        assert_eq!(Profile::parse(""), Profile::default());
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut profile = Profile::default();
+        profile.apply(&Changes::from([
+            (Field::Job, "game developer".to_string()),
+            (Field::Age, "27".to_string()),
+        ]));
+        profile.locked.insert(Field::Age);
+        profile.fields.insert(Field::Notes, "Quiet.".to_string());
+        let json = profile.to_json();
+        assert_eq!(
+            json,
+            r#"{"age":"27","job":"game developer","locked":["age"],"notes":"Quiet."}"#
+        );
+        assert_eq!(Profile::parse(&json), profile);
+        assert_eq!(profile.render(), "age: 27; job: game developer. Quiet.");
+    }
+
+    #[test]
+    fn locked_fields_are_kept() {
+        let mut profile = Profile::parse(r#"{"age":"27","likes":"tea","locked":["age"]}"#);
+        let changes = parse_changes(r#"{"age": 99, "likes": ["cats", "dogs"], "mood": "happy"}"#);
+        assert_eq!(profile.unlocked(&changes).len(), 1);
+
+        let changed = profile.apply(&changes);
+        assert_eq!(changed, [Field::Likes]);
+        assert_eq!(profile.fields[&Field::Age], "27");
+        assert_eq!(profile.fields[&Field::Likes], "cats, dogs");
+
+        // nothing new
+        assert!(profile.apply(&changes).is_empty());
+        // empty values clear a field
+        assert_eq!(
+            profile.apply(&Changes::from([(Field::Likes, String::new())])),
+            [Field::Likes]
+        );
+        assert!(!profile.fields.contains_key(&Field::Likes));
+    }
+
+    #[test]
+    fn unstructured_changes_are_notes() {
+        assert_eq!(
+            parse_changes("Says hello a lot."),
+            Changes::from([(Field::Notes, "Says hello a lot.".to_string())])
+        );
+    }
+
+    #[tokio::test]
+    async fn approving_skips_fields_locked_since() {
+        use crate::database::MemoryDatabase;
+
+        let database = Database::with_storage(MemoryDatabase::new());
+        database
+            .update_user(
+                ProfileScope::Guild(1),
+                "42",
+                "Bob",
+                r#"{"age":"27","locked":["age"]}"#,
+                RevisionSource::Admin,
+                None,
+            )
+            .await
+            .unwrap();
+        let id = database
+            .add_profile_proposal(&DbProfileProposal {
+                id: 0,
+                user_id: "42".to_string(),
+                scope: "1".to_string(),
+                name: "Bob".to_string(),
+                changes: r#"{"age":"12","job":"developer"}"#.to_string(),
+                model_response: Some("response".to_string()),
+                created_at: chrono::Utc::now().naive_utc(),
+            })
+            .await
+            .unwrap();
+        let proposal = database.get_profile_proposal(id).await.unwrap().unwrap();
+
+        assert_eq!(approve(&database, &proposal).await.unwrap(), [Field::Job]);
+        let user = get_profile(&database, ProfileScope::Guild(1), "42")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Profile::parse(&user.info).render(),
+            "age: 27; job: developer"
+        );
+        assert!(database
+            .get_profile_proposals(None)
+            .await
+            .unwrap()
+            .is_empty());
+        let history = database
+            .get_user_history(ProfileScope::Guild(1), "42", 1)
+            .await
+            .unwrap();
+        assert_eq!(history[0].model_response.as_deref(), Some("response"));
+    }
+}
@@ -1,29 +1,241 @@
-use askama::Template;
-use chrono::Utc;
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use itertools::Itertools;
+use minijinja::context;
+use serde::{Deserialize, Serialize};
 
-use crate::database::{DbSummary, DbUser};
+use crate::config::{Clock, TimeConfig};
+use crate::database::{
+    DbPeriodSummary, DbSummary, DbUser, MessageFilter, MessageSearch, ProfileScope, SummaryLevel,
+    KASUMI_ID,
+};
 use crate::gpt::{GptMessage, GptRole};
-use crate::{Database, DbMessage};
+use crate::profile::Profile;
+use crate::templates::{self, quote, Location};
+use crate::Database;
+
+/// How the chat log is laid out for the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptLayout {
+    /// The chat log in the system prompt, asking who speaks next and what they say.
+    #[default]
+    Transcript,
+    /// The chat log as conversation turns, with Kasumi's messages as the assistant's.
+    Turns,
+}
+
+impl fmt::Display for PromptLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PromptLayout::Transcript => "transcript",
+            PromptLayout::Turns => "turns",
+        })
+    }
+}
+
+/// What the chat templates render with.
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptContext {
+    pub guild: Option<u64>,
+    pub channel: u64,
+    pub users: Vec<ChatUser>,
+    pub date: String,
+    pub time: String,
+    /// IANA name of the time zone of `date`, `time` and message times.
+    pub timezone: String,
+    pub summary: String,
+    pub history: Vec<HistorySummary>,
+    pub messages: Vec<ChatMessage>,
+}
+
+impl PromptContext {
+    pub fn location(&self) -> Location {
+        Location {
+            guild: self.guild,
+            channel: self.channel,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistorySummary {
+    pub period: String,
+    pub summary: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatUser {
+    #[serde(skip)]
+    pub id: String,
+    pub name: String,
+    pub aliases: String,
+    pub info: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    /// Local time it was sent, with the date if it's the first message of the day.
+    pub time: String,
+    /// How long after the previous message, if it was a while.
+    pub gap: Option<String>,
+    #[serde(skip)]
+    pub sender_id: String,
+    pub sender: String,
+    pub message: String,
+}
+
+/// What the profile template renders with: the chat context and whose profile it updates.
+#[derive(Serialize)]
+struct ProfileContext<'a> {
+    #[serde(flatten)]
+    context: &'a PromptContext,
+    participant: ProfileParticipant<'a>,
+}
+
+#[derive(Serialize)]
+struct ProfileParticipant<'a> {
+    id: &'a str,
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct RecalledMessage<'a> {
+    date: String,
+    sender: &'a str,
+    message: &'a str,
+}
 
-#[derive(Template)]
-#[template(path = "chat.txt")]
-pub struct ChatSystem<'a> {
-    pub users: &'a [DbUser],
-    pub date: &'a str,
-    pub time: &'a str,
-    pub summary: &'a str,
-    pub messages: &'a [DbMessage],
+/// Someone who wrote in the chat log of a prompt.
+#[derive(Debug, Clone)]
+pub struct Participant {
+    pub id: String,
+    pub name: String,
+    /// The name in the prompt, numbered if it clashes with someone else's or Kasumi's.
+    pub label: String,
 }
 
-pub const CHAT_USER_PROMPT: &str = include_str!("../templates/chat_user.txt");
-pub const CHAT_SUMMARY_PROMPT: &str = include_str!("../templates/summary_user.txt");
+#[derive(Debug)]
+pub struct PromptInfo {
+    pub message_count: usize,
+    /// Profile scope of the channel, `None` if no message tells it.
+    pub scope: Option<ProfileScope>,
+    /// Everyone except Kasumi, with the name they currently use.
+    pub participants: Vec<Participant>,
+    /// Time of the oldest message in the chat log.
+    pub window_start: Option<NaiveDateTime>,
+    /// Time zone and locale of the channel's server.
+    pub clock: Clock,
+    pub context: PromptContext,
+}
+
+/// Numbers the participants whose names clash with each other's or Kasumi's,
+/// so every line of the chat log and every profile belongs to one person.
+fn label_participants(participants: &mut [Participant]) {
+    let mut counts = HashMap::new();
+    for participant in participants.iter() {
+        *counts.entry(participant.name.to_lowercase()).or_insert(0) += 1;
+    }
+    let mut numbers = HashMap::new();
+    for participant in participants.iter_mut() {
+        let key = participant.name.to_lowercase();
+        if counts[&key] > 1 || key == "kasumi" {
+            let number = numbers.entry(key).or_insert(0);
+            *number += 1;
+            participant.label = format!("{} #{}", participant.name, number);
+        }
+    }
+}
+
+/// Pauses in the chat log at least this long are shown.
+const GAP_MINUTES: i64 = 30;
+
+pub fn local_time(clock: &Clock, time: NaiveDateTime) -> DateTime<Tz> {
+    Utc.from_utc_datetime(&time).with_timezone(&clock.timezone)
+}
+
+/// How long a pause in the chat log was, e.g. "3 hours later".
+fn gap_label(gap: Duration) -> String {
+    let (count, unit) = if gap < Duration::hours(1) {
+        (gap.num_minutes(), "minute")
+    } else if gap < Duration::days(2) {
+        (gap.num_hours(), "hour")
+    } else {
+        (gap.num_days(), "day")
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{} {}{} later", count, unit, plural)
+}
+
+/// What part of the history a summary covers, as prompts show it.
+pub fn period_label(summary: &DbPeriodSummary, clock: &Clock) -> String {
+    let start = local_time(clock, summary.period_start);
+    let end = local_time(clock, summary.period_end);
+    let format = |time: &DateTime<Tz>, format| time.format_localized(format, clock.locale);
+    match summary.level.parse() {
+        Ok(SummaryLevel::Session) if start.date_naive() == end.date_naive() => format!(
+            "{}, {}-{}",
+            format(&end, "%-d %B %Y"),
+            format(&start, "%H:%M"),
+            format(&end, "%H:%M")
+        ),
+        Ok(SummaryLevel::Session) => format!(
+            "{} - {}",
+            format(&start, "%-d %B %Y %H:%M"),
+            format(&end, "%-d %B %Y %H:%M")
+        ),
+        Ok(SummaryLevel::Daily) => format(&end, "%-d %B %Y").to_string(),
+        Ok(SummaryLevel::Weekly) => {
+            let monday =
+                end.date_naive() - Duration::days(end.weekday().num_days_from_monday().into());
+            format!(
+                "Week of {}",
+                monday.format_localized("%-d %B %Y", clock.locale)
+            )
+        }
+        Ok(SummaryLevel::LongTerm) | Err(_) => format!("Until {}", format(&end, "%-d %B %Y")),
+    }
+}
 
-async fn get_system_prompt(
+/// The summaries that fit in `budget` characters, oldest first.
+/// The most recent one goes first, then the long-term one, then the rest from new to old;
+/// ones that don't fit are left out.
+pub fn select_summaries(summaries: &[DbPeriodSummary], budget: usize) -> Vec<&DbPeriodSummary> {
+    let long_term = SummaryLevel::LongTerm.to_string();
+    let mut order = (0..summaries.len()).rev().collect::<Vec<_>>();
+    if let Some(position) = order
+        .iter()
+        .skip(1)
+        .position(|&i| summaries[i].level == long_term)
+    {
+        let index = order.remove(position + 1);
+        order.insert(1, index);
+    }
+
+    let mut left = budget;
+    let mut selected = Vec::new();
+    for i in order {
+        let length = summaries[i].summary.chars().count();
+        if length <= left {
+            left -= length;
+            selected.push(i);
+        }
+    }
+    selected.sort();
+    selected.into_iter().map(|i| &summaries[i]).collect()
+}
+
+/// Gathers what the chat templates show about a channel.
+pub async fn prompt_context(
     database: &Database,
     channel: u64,
     min_count: i64,
-) -> anyhow::Result<(String, usize)> {
+    summary_chars: usize,
+    time: &TimeConfig,
+) -> anyhow::Result<PromptInfo> {
     let DbSummary {
         summary,
         last_update,
@@ -33,50 +245,773 @@ async fn get_system_prompt(
     let messages = database
         .get_messages(channel, last_update, min_count)
         .await?;
+    let scope = messages
+        .last()
+        .and_then(|m| m.scope.parse().ok())
+        .filter(|scope| *scope != ProfileScope::Legacy);
+    let guild = match scope {
+        Some(ProfileScope::Guild(guild)) => Some(guild),
+        _ => None,
+    };
+    let clock = time.clock(guild);
 
-    let mut users = messages
+    let summaries = database.get_period_summaries(channel).await?;
+    let history = select_summaries(&summaries, summary_chars)
+        .into_iter()
+        .map(|s| HistorySummary {
+            period: period_label(s, &clock),
+            summary: s.summary.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    // the latest name each sender used in this channel
+    let mut names = HashMap::new();
+    for message in &messages {
+        names.insert(message.sender_id.as_str(), message.sender.as_str());
+    }
+    names.insert(KASUMI_ID, "Kasumi");
+
+    let mut participants = messages
         .iter()
-        .filter(|m| m.sender != "Kasumi")
-        .map(|m| m.sender.to_string())
+        .map(|m| m.sender_id.as_str())
+        .filter(|id| *id != KASUMI_ID)
         .unique()
+        .map(|id| Participant {
+            id: id.to_string(),
+            name: names[id].to_string(),
+            label: names[id].to_string(),
+        })
         .collect::<Vec<_>>();
-    users.push("Kasumi".to_string());
+    label_participants(&mut participants);
+    let mut labels = participants
+        .iter()
+        .map(|p| (p.id.as_str(), p.label.as_str()))
+        .collect::<HashMap<_, _>>();
+    labels.insert(KASUMI_ID, "Kasumi");
 
-    let users = database.get_users(&users).await?;
+    let mut ids = participants
+        .iter()
+        .map(|p| p.id.clone())
+        .collect::<Vec<_>>();
+    ids.push(KASUMI_ID.to_string());
 
-    let now = Utc::now();
-    let date = now.format("%e %B %Y, %A").to_string();
-    let time = now.format("%r").to_string();
+    let user_names = database.get_user_names(&ids).await?;
+    // a shared global profile replaces the one from this scope
+    let users = database
+        .get_users(scope.unwrap_or(ProfileScope::Legacy), &ids)
+        .await?
+        .into_iter()
+        .sorted_by_key(|u| u.scope != ProfileScope::Global.to_string())
+        .unique_by(|u| u.id.clone())
+        .map(|DbUser { id, name, info, .. }| {
+            let name = names.get(id.as_str()).map_or(name, |n| n.to_string());
+            let aliases = user_names
+                .iter()
+                .filter(|n| n.user_id == id && n.name != name)
+                .map(|n| n.name.as_str())
+                .take(3)
+                .join(", ");
+            ChatUser {
+                id: id.clone(),
+                name: labels.get(id.as_str()).map_or(name, |l| l.to_string()),
+                aliases,
+                info: Profile::parse(&info).render(),
+            }
+        })
+        .collect::<Vec<_>>();
 
-    Ok((
-        ChatSystem {
-            users: &users[..],
-            date: &date,
-            time: &time,
-            summary: &summary,
-            messages: &messages[..],
-        }
-        .render()?,
-        messages.len(),
-    ))
+    let mut previous: Option<NaiveDateTime> = None;
+    let mut chat_messages = Vec::new();
+    for m in &messages {
+        let local = local_time(&clock, m.date_time);
+        // the date goes with the first message of every day
+        let same_day = previous.map(|p| local_time(&clock, p).date_naive() == local.date_naive());
+        let format = if same_day == Some(true) {
+            "%H:%M"
+        } else {
+            "%-d %B %Y, %H:%M"
+        };
+        chat_messages.push(ChatMessage {
+            time: local.format_localized(format, clock.locale).to_string(),
+            gap: previous
+                .map(|p| m.date_time - p)
+                .filter(|gap| *gap >= Duration::minutes(GAP_MINUTES))
+                .map(gap_label),
+            sender_id: m.sender_id.clone(),
+            sender: labels[m.sender_id.as_str()].to_string(),
+            message: m.message.clone(),
+        });
+        previous = Some(m.date_time);
+    }
+
+    let now = Utc::now().with_timezone(&clock.timezone);
+    let date = now
+        .format_localized("%-d %B %Y, %A", clock.locale)
+        .to_string();
+    let time = now.format_localized("%X", clock.locale).to_string();
+
+    Ok(PromptInfo {
+        message_count: messages.len(),
+        scope,
+        participants,
+        window_start: messages.first().map(|m| m.date_time),
+        clock,
+        context: PromptContext {
+            guild,
+            channel,
+            users,
+            date,
+            time,
+            timezone: clock.timezone.name().to_string(),
+            summary,
+            history,
+            messages: chat_messages,
+        },
+    })
 }
 
+/// The chat prompt of a channel, asking what the `prompt` template says.
 pub async fn get_prompt(
     database: &Database,
     channel_id: u64,
-    user_prompt: &str,
+    prompt: &str,
     min_count: i64,
-) -> anyhow::Result<(Vec<GptMessage>, usize)> {
-    let (system_prompt, message_count) = get_system_prompt(database, channel_id, min_count).await?;
+    summary_chars: usize,
+    time: &TimeConfig,
+) -> anyhow::Result<(Vec<GptMessage>, PromptInfo)> {
+    let info = prompt_context(database, channel_id, min_count, summary_chars, time).await?;
+    let templates = templates::get();
+    let location = info.context.location();
     let gpt_request = vec![
         GptMessage {
             role: GptRole::System,
-            content: system_prompt,
+            content: templates.render(templates::CHAT, location, &info.context)?,
+            name: None,
         },
         GptMessage {
             role: GptRole::User,
-            content: user_prompt.to_string(),
+            content: templates.render(prompt, location, &info.context)?,
+            name: None,
         },
     ];
-    Ok((gpt_request, message_count))
+    Ok((gpt_request, info))
+}
+
+/// Asks for what a participant's own messages in the chat log say about them.
+/// Everyone else's messages and the summaries are left out, so nobody else can
+/// write into the profile.
+pub fn get_profile_prompt(
+    info: &PromptInfo,
+    participant: &Participant,
+) -> anyhow::Result<Vec<GptMessage>> {
+    let mut context = info.context.clone();
+    context.messages.retain(|m| m.sender_id == participant.id);
+    context.users.retain(|u| u.id == participant.id);
+    context.summary.clear();
+    context.history.clear();
+    let templates = templates::get();
+    let location = context.location();
+    let profile = ProfileContext {
+        context: &context,
+        participant: ProfileParticipant {
+            id: &participant.id,
+            name: &participant.label,
+        },
+    };
+    Ok(vec![
+        GptMessage {
+            role: GptRole::System,
+            content: templates.render(templates::CHAT, location, &context)?,
+            name: None,
+        },
+        GptMessage {
+            role: GptRole::User,
+            content: templates.render(templates::PROFILE_USER, location, &profile)?,
+            name: None,
+        },
+    ])
+}
+
+/// The chat prompt of a channel as conversation turns: the chat templates' context without
+/// the chat log, then every message as a turn of its own, Kasumi's as the assistant's.
+pub async fn get_turns_prompt(
+    database: &Database,
+    channel_id: u64,
+    system_template_name: &str,
+    min_count: i64,
+    summary_chars: usize,
+    time: &TimeConfig,
+) -> anyhow::Result<(Vec<GptMessage>, PromptInfo)> {
+    let info = prompt_context(database, channel_id, min_count, summary_chars, time).await?;
+    let templates = templates::get();
+    let location = info.context.location();
+    let mut gpt_request = vec![GptMessage {
+        role: GptRole::System,
+        content: templates.render(system_template_name, location, &info.context)?,
+        name: None,
+    }];
+    for message in &info.context.messages {
+        // labels of people named Kasumi are numbered, so this is only ever Kasumi
+        if message.sender == "Kasumi" {
+            gpt_request.push(GptMessage {
+                role: GptRole::Assistant,
+                content: message.message.clone(),
+                name: None,
+            });
+            continue;
+        }
+        let gap = message
+            .gap
+            .as_ref()
+            .map_or(String::new(), |gap| format!("({}) ", gap));
+        gpt_request.push(GptMessage {
+            role: GptRole::User,
+            content: format!(
+                "{}[{}] {}: {}",
+                gap,
+                message.time,
+                quote(&message.sender),
+                quote(&message.message)
+            ),
+            name: turn_name(&message.sender),
+        });
+    }
+    Ok((gpt_request, info))
+}
+
+/// A sender label as the API takes names: letters, digits, `_` and `-`, at most 64 of them.
+fn turn_name(label: &str) -> Option<String> {
+    let name = label
+        .chars()
+        .map(|c| if c == ' ' || c == '#' { '_' } else { c })
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .take(64)
+        .collect::<String>();
+    (!name.trim_matches('_').is_empty()).then_some(name)
+}
+
+/// The time zone and locale of a channel, from the server of its latest message.
+pub async fn channel_clock(
+    database: &Database,
+    channel: u64,
+    time: &TimeConfig,
+) -> anyhow::Result<Clock> {
+    let latest = database
+        .get_messages(channel, Utc::now().naive_utc(), 1)
+        .await?;
+    let guild = match latest.last().and_then(|m| m.scope.parse().ok()) {
+        Some(ProfileScope::Guild(guild)) => Some(guild),
+        _ => None,
+    };
+    Ok(time.clock(guild))
+}
+
+/// Asks to combine summaries into one.
+pub fn get_rollup_prompt(
+    parts: &[&DbPeriodSummary],
+    clock: &Clock,
+) -> anyhow::Result<Vec<GptMessage>> {
+    let location = Location {
+        guild: None,
+        channel: parts
+            .first()
+            .and_then(|s| s.channel.parse().ok())
+            .unwrap_or(0),
+    };
+    let parts = parts
+        .iter()
+        .map(|s| HistorySummary {
+            period: period_label(s, clock),
+            summary: s.summary.clone(),
+        })
+        .collect::<Vec<_>>();
+    let templates = templates::get();
+    let context = context! { parts };
+    Ok(vec![
+        GptMessage {
+            role: GptRole::System,
+            content: templates.render(templates::ROLLUP, location, &context)?,
+            name: None,
+        },
+        GptMessage {
+            role: GptRole::User,
+            content: templates.render(templates::ROLLUP_USER, location, &context)?,
+            name: None,
+        },
+    ])
+}
+
+/// Searches the history of the channel from before the chat log, and asks for the
+/// reply again with what was found. Other channels stay out, their audience may differ.
+pub async fn get_recall_prompt(
+    database: &Database,
+    channel_id: u64,
+    info: &PromptInfo,
+    query: &str,
+    limit: i64,
+) -> anyhow::Result<String> {
+    let mut messages = database
+        .search_messages(&MessageSearch {
+            text: query.to_string(),
+            filter: MessageFilter {
+                channel: Some(channel_id),
+                until: info.window_start,
+                ..Default::default()
+            },
+            sender_id: None,
+            limit,
+        })
+        .await?;
+    messages.sort_by_key(|m| m.date_time);
+
+    let recalled = messages
+        .iter()
+        .map(|m| RecalledMessage {
+            date: local_time(&info.clock, m.date_time)
+                .format("%Y-%m-%d %H:%M")
+                .to_string(),
+            sender: &m.sender,
+            message: &m.message,
+        })
+        .collect::<Vec<_>>();
+    let templates = templates::get();
+    let location = info.context.location();
+    let user_prompt = templates.render(templates::CHAT_USER, location, &info.context)?;
+    templates.render(
+        templates::RECALL,
+        location,
+        context! { query, messages => recalled, user_prompt },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::config::GuildTime;
+    use crate::database::{DbMessage, MemoryDatabase, RevisionSource};
+
+    fn message(sender_id: &str, sender: &str, text: &str, seconds: i64) -> DbMessage {
+        DbMessage {
+            channel: "1".to_string(),
+            scope: "10".to_string(),
+            sender_id: sender_id.to_string(),
+            sender: sender.to_string(),
+            message: text.to_string(),
+            date_time: Utc::now().naive_utc() + Duration::seconds(seconds),
+            message_id: None,
+            raw_message: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn prompt_uses_current_names_and_channel_profiles() {
+        let database = Database::with_storage(MemoryDatabase::new());
+        database
+            .update_user(
+                ProfileScope::Global,
+                KASUMI_ID,
+                "Kasumi",
+                "A cheerful bot.",
+                RevisionSource::Admin,
+                None,
+            )
+            .await
+            .unwrap();
+        database
+            .update_user(
+                ProfileScope::Guild(10),
+                "42",
+                "Bob",
+                "Likes cats.",
+                RevisionSource::Admin,
+                None,
+            )
+            .await
+            .unwrap();
+        database
+            .update_user(
+                ProfileScope::Guild(20),
+                "42",
+                "Bob",
+                "Other guild.",
+                RevisionSource::Admin,
+                None,
+            )
+            .await
+            .unwrap();
+        database
+            .touch_user(ProfileScope::Guild(10), "42", "bob", "Bob")
+            .await
+            .unwrap();
+        database
+            .touch_user(ProfileScope::Guild(10), "42", "bob", "Bobby")
+            .await
+            .unwrap();
+        database
+            .update_summary(1, "Bob arrived.", RevisionSource::Admin, None, None)
+            .await
+            .unwrap();
+        for message in [
+            message("42", "Bob", "hi", 1),
+            message(KASUMI_ID, "Kasumi", "hello Bob", 2),
+            message("42", "Bobby", "call me Bobby", 3),
+        ] {
+            database.add_message(&message).await.unwrap();
+        }
+
+        let (prompt, info) = get_prompt(
+            &database,
+            1,
+            templates::CHAT_USER,
+            0,
+            4000,
+            &TimeConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(info.message_count, 3);
+        assert_eq!(info.scope, Some(ProfileScope::Guild(10)));
+        assert_eq!(info.participants.len(), 1);
+        assert_eq!(info.participants[0].id, "42");
+        assert_eq!(info.participants[0].name, "Bobby");
+
+        let system = &prompt[0].content;
+        assert!(system.contains("Bob arrived."));
+        assert!(system.contains("A cheerful bot."));
+        assert!(system.contains("USER Bobby INFO Also known as Bob. Likes cats. END"));
+        assert!(!system.contains("Other guild."));
+        assert!(system.contains("USER Bobby SAYS call me Bobby END"));
+        assert!(prompt[1].content.contains("USER {nickname} SAYS"));
+    }
+
+    #[tokio::test]
+    async fn chat_log_is_in_the_server_time_zone_and_locale() {
+        let database = Database::with_storage(MemoryDatabase::new());
+        let at = |time| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+        for message in [
+            DbMessage {
+                date_time: at("2023-04-24 21:30"),
+                ..message("42", "Bob", "good night", 0)
+            },
+            DbMessage {
+                date_time: at("2023-04-24 21:40"),
+                ..message("43", "Eve", "night", 0)
+            },
+            DbMessage {
+                date_time: at("2023-04-25 01:00"),
+                ..message("42", "Bob", "can't sleep", 0)
+            },
+        ] {
+            database.add_message(&message).await.unwrap();
+        }
+        let time = TimeConfig {
+            guilds: vec![GuildTime {
+                guild: 10,
+                timezone: Some("Europe/Moscow".to_string()),
+                locale: Some("ru_RU".to_string()),
+            }],
+            ..Default::default()
+        };
+
+        let (prompt, _) = get_prompt(&database, 1, templates::CHAT_USER, 0, 4000, &time)
+            .await
+            .unwrap();
+        let system = &prompt[0].content;
+        assert!(system.contains("(Europe/Moscow)"));
+        assert!(system.contains("[25 апреля 2023, 00:30] USER Bob SAYS good night END\n"));
+        assert!(system.contains("[00:40] USER Eve SAYS night END\n(3 hours later)\n[04:00]"));
+
+        let (prompt, _) = get_prompt(
+            &database,
+            1,
+            templates::CHAT_USER,
+            0,
+            4000,
+            &TimeConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert!(prompt[0]
+            .content
+            .contains("[24 April 2023, 21:30] USER Bob"));
+        assert!(prompt[0].content.contains("(UTC)"));
+    }
+
+    #[tokio::test]
+    async fn turns_are_laid_out_by_role() {
+        let database = Database::with_storage(MemoryDatabase::new());
+        for message in [
+            message("42", "Bob", "hi", 1),
+            message(KASUMI_ID, "Kasumi", "hello Bob", 2),
+            message("43", "Кася", "привет", 3),
+            message("44", "Kasumi", "I'm Kasumi too", 4),
+        ] {
+            database.add_message(&message).await.unwrap();
+        }
+
+        let (prompt, info) = get_turns_prompt(
+            &database,
+            1,
+            templates::CHAT_TURNS,
+            0,
+            4000,
+            &TimeConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(info.message_count, 4);
+        assert!(matches!(prompt[0].role, GptRole::System));
+        assert!(!prompt[0].content.contains("hello Bob"));
+
+        let turns = prompt[1..]
+            .iter()
+            .map(|m| (&m.role, m.name.as_deref()))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            turns[..],
+            [
+                (GptRole::User, Some("Bob")),
+                (GptRole::Assistant, None),
+                (GptRole::User, None),
+                (GptRole::User, Some("Kasumi__1")),
+            ]
+        ));
+        assert!(prompt[1].content.ends_with("] Bob: hi"));
+        assert_eq!(prompt[2].content, "hello Bob");
+        assert!(prompt[3].content.ends_with("] Кася: привет"));
+        assert!(prompt[4].content.ends_with("] Kasumi #1: I'm Kasumi too"));
+    }
+
+    #[test]
+    fn history_keeps_the_newest_and_long_term_summaries_within_budget() {
+        let start = NaiveDateTime::from_timestamp_opt(1_682_300_000, 0).unwrap();
+        let summary = |level: SummaryLevel, days: i64, text: &str| DbPeriodSummary {
+            id: 0,
+            channel: "1".to_string(),
+            level: level.to_string(),
+            period_start: start + Duration::days(days),
+            period_end: start + Duration::days(days) + Duration::hours(1),
+            summary: text.to_string(),
+            created_at: start,
+        };
+        let summaries = [
+            summary(SummaryLevel::LongTerm, 0, "long ago"),
+            summary(SummaryLevel::Weekly, 7, "a week of talking"),
+            summary(SummaryLevel::Daily, 14, "one day"),
+            summary(SummaryLevel::Session, 15, "just now"),
+        ];
+
+        let texts = |budget| {
+            select_summaries(&summaries, budget)
+                .iter()
+                .map(|s| s.summary.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texts(1000).len(), 4);
+        assert_eq!(texts(24), ["long ago", "one day", "just now"]);
+        assert_eq!(texts(10), ["just now"]);
+        assert!(texts(0).is_empty());
+
+        let utc = TimeConfig::default().clock(None);
+        assert_eq!(period_label(&summaries[0], &utc), "Until 24 April 2023");
+        assert_eq!(period_label(&summaries[1], &utc), "Week of 1 May 2023");
+        assert_eq!(period_label(&summaries[3], &utc), "9 May 2023, 01:33-02:33");
+        let moscow = TimeConfig {
+            timezone: "Europe/Moscow".to_string(),
+            locale: "ru_RU".to_string(),
+            ..Default::default()
+        }
+        .clock(None);
+        assert_eq!(
+            period_label(&summaries[3], &moscow),
+            "9 мая 2023, 04:33-05:33"
+        );
+    }
+
+    #[tokio::test]
+    async fn recall_searches_before_the_chat_log_in_the_same_channel() {
+        let database = Database::with_storage(MemoryDatabase::new());
+        for message in [
+            message("42", "Bob", "my cat is called Mochi", -3600),
+            DbMessage {
+                channel: "3".to_string(),
+                scope: "20".to_string(),
+                ..message("43", "Eve", "my cat is called Secret", -3500)
+            },
+            // a staff channel of the same server
+            DbMessage {
+                channel: "2".to_string(),
+                ..message("44", "Mod", "the cat called Staffonly gets banned", -3400)
+            },
+            message("42", "Bob", "what was my cat called?", 1),
+        ] {
+            database.add_message(&message).await.unwrap();
+        }
+
+        let (_, info) = get_prompt(
+            &database,
+            1,
+            templates::CHAT_USER,
+            0,
+            4000,
+            &TimeConfig::default(),
+        )
+        .await
+        .unwrap();
+        let info = PromptInfo {
+            window_start: Some(Utc::now().naive_utc()),
+            ..info
+        };
+        let prompt = get_recall_prompt(&database, 1, &info, "cat called", 5)
+            .await
+            .unwrap();
+        assert!(prompt.contains("USER Bob SAYS my cat is called Mochi END"));
+        assert!(!prompt.contains("Secret"));
+        assert!(!prompt.contains("Staffonly"));
+        assert!(!prompt.contains("what was my cat called?"));
+        assert!(prompt.ends_with("USER {nickname} SAYS {predicted message} END"));
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    use std::time::{Duration, Instant};
+
+    use chrono::{Duration as ChronoDuration, NaiveDateTime};
+    use sqlx::sqlite::SqlitePool;
+    use tracing::info;
+    use tracing_subscriber::filter;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::Layer;
+
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::database::RevisionSource;
+    use crate::DbMessage;
+
+    const CHANNELS: u64 = 20;
+    const MESSAGES_PER_CHANNEL: i64 = 5_000;
+    /// Messages after the summary, the ones the prompt gets by date.
+    const NEW_MESSAGES: i64 = 20;
+    const RUNS: u32 = 200;
+
+    fn message(channel: u64, i: i64, date_time: NaiveDateTime) -> DbMessage {
+        DbMessage {
+            channel: channel.to_string(),
+            scope: "1".to_string(),
+            sender_id: (i % 7).to_string(),
+            sender: format!("user{}", i % 7),
+            message: format!("message number {} in channel {}", i, channel),
+            date_time,
+            message_id: None,
+            raw_message: None,
+        }
+    }
+
+    /// Average and slowest time to assemble a prompt.
+    async fn time_prompts(database: &Database) -> (Duration, Duration) {
+        let mut total = Duration::ZERO;
+        let mut slowest = Duration::ZERO;
+        for run in 0..RUNS {
+            let started = Instant::now();
+            let (_, info) = get_prompt(
+                database,
+                u64::from(run) % CHANNELS,
+                templates::CHAT_USER,
+                6,
+                4000,
+                &TimeConfig::default(),
+            )
+            .await
+            .unwrap();
+            let elapsed = started.elapsed();
+            assert_eq!(info.message_count, NEW_MESSAGES as usize);
+            total += elapsed;
+            slowest = slowest.max(elapsed);
+        }
+        (total / RUNS, slowest)
+    }
+
+    /// Prompt assembly latency with a large history, with and without the message index.
+    /// Run with `cargo test --release prompt_assembly -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn prompt_assembly_latency() {
+        let _ = tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .compact()
+                    .with_test_writer()
+                    .with_filter(filter::filter_fn(|data| data.target() != "sqlx::query")),
+            )
+            .try_init();
+        let path = std::env::temp_dir().join(format!("kasumi_bench_{}.db", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        let database = Database::new(&DatabaseConfig {
+            url: url.clone(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        // the history, then the summary, then what was said since
+        let start = NaiveDateTime::from_timestamp_opt(1_680_000_000, 0).unwrap();
+        for i in 0..MESSAGES_PER_CHANNEL - NEW_MESSAGES {
+            for channel in 0..CHANNELS {
+                let date_time = start + ChronoDuration::seconds(i);
+                database
+                    .add_message(&message(channel, i, date_time))
+                    .await
+                    .unwrap();
+            }
+        }
+        for channel in 0..CHANNELS {
+            database
+                .update_summary(
+                    channel,
+                    "Everyone was talking.",
+                    RevisionSource::Admin,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+        let now = Utc::now().naive_utc();
+        for i in 0..NEW_MESSAGES {
+            for channel in 0..CHANNELS {
+                let date_time = now + ChronoDuration::seconds(i + 1);
+                database
+                    .add_message(&message(channel, i, date_time))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let messages = CHANNELS as i64 * MESSAGES_PER_CHANNEL;
+        let (average, slowest) = time_prompts(&database).await;
+        info!(
+            "{} messages with the index: prompt assembly took {:?} on average, {:?} at most",
+            messages, average, slowest
+        );
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        sqlx::query("DROP INDEX messages_channel_date_time")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        let (average, slowest) = time_prompts(&database).await;
+        info!(
+            "{} messages without the index: prompt assembly took {:?} on average, {:?} at most",
+            messages, average, slowest
+        );
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
 }
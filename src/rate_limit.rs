@@ -0,0 +1,54 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Users tracked before the ones without recent replies are dropped.
+const MAX_USERS: usize = 1000;
+
+/// Counts the replies to each user within a sliding window.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    replies: Arc<Mutex<HashMap<u64, VecDeque<Instant>>>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a reply to the user, unless `max` replies were already counted within `window`.
+    pub fn try_reply(&self, user: u64, max: usize, window: Duration) -> bool {
+        let mut replies = self.replies.lock().unwrap();
+        if replies.len() >= MAX_USERS {
+            replies.retain(|_, times| times.back().is_some_and(|t| t.elapsed() < window));
+        }
+        let times = replies.entry(user).or_default();
+        while times.front().is_some_and(|t| t.elapsed() >= window) {
+            times.pop_front();
+        }
+        if times.len() >= max {
+            return false;
+        }
+        times.push_back(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_replies_per_user() {
+        let limiter = RateLimiter::new();
+        let window = Duration::from_secs(60);
+        assert!(limiter.try_reply(1, 2, window));
+        assert!(limiter.try_reply(1, 2, window));
+        assert!(!limiter.try_reply(1, 2, window));
+        assert!(limiter.try_reply(2, 2, window));
+
+        // replies older than the window don't count
+        assert!(limiter.try_reply(3, 1, Duration::ZERO));
+        assert!(limiter.try_reply(3, 1, Duration::ZERO));
+    }
+}
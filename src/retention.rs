@@ -0,0 +1,280 @@
+use std::fmt;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::{self, Config, RetentionPolicy};
+use crate::Database;
+
+/// What happens to messages that prompts no longer read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionAction {
+    /// Keep them in the messages table.
+    #[default]
+    Keep,
+    /// Move them to the compressed message archive.
+    Archive,
+    /// Delete them for good.
+    Delete,
+}
+
+/// What one pruning pass did.
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    /// Channels that had messages pruned.
+    pub channels: usize,
+    /// Messages moved to the archive.
+    pub archived: u64,
+    /// Messages deleted.
+    pub deleted: u64,
+    /// Channels left alone because they were never summarized.
+    pub unsummarized: usize,
+    /// Channels whose pruning failed with a database error.
+    pub failed: usize,
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Pruned {} channels: {} messages archived, {} deleted",
+            self.channels, self.archived, self.deleted
+        )?;
+        if self.unsummarized > 0 {
+            write!(f, ", {} channels not summarized yet", self.unsummarized)?;
+        }
+        if self.failed > 0 {
+            write!(f, ", {} channels failed", self.failed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies the retention policy of every channel.
+pub async fn prune_now(database: &Database, config: &Config) -> PruneReport {
+    info!("Pruning messages");
+    let mut report = PruneReport::default();
+    let channels = match database.channel_list().await {
+        Ok(channels) => channels,
+        Err(e) => {
+            warn!("Failed to get channels: {:?}", e);
+            return report;
+        }
+    };
+
+    for channel in channels {
+        let policy = config.retention.policy(channel);
+        if policy.action == RetentionAction::Keep {
+            continue;
+        }
+
+        let before = match prune_before(database, channel, policy, config.chat.min_messages).await {
+            Ok(Some(before)) => before,
+            Ok(None) => {
+                report.unsummarized += 1;
+                continue;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to get retention cutoff for channel {}: {:?}",
+                    channel, e
+                );
+                report.failed += 1;
+                continue;
+            }
+        };
+
+        let pruned = match policy.action {
+            RetentionAction::Archive => database.archive_messages(channel, before).await,
+            RetentionAction::Delete => database.delete_messages(channel, before).await,
+            RetentionAction::Keep => unreachable!(),
+        };
+        match pruned {
+            Ok(0) => {}
+            Ok(count) => {
+                info!(
+                    "Pruned {} messages of channel {} ({:?})",
+                    count, channel, policy.action
+                );
+                report.channels += 1;
+                if policy.action == RetentionAction::Archive {
+                    report.archived += count;
+                } else {
+                    report.deleted += count;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to prune channel {}: {:?}", channel, e);
+                report.failed += 1;
+            }
+        }
+    }
+
+    info!("{}", report);
+    report
+}
+
+/// Messages before the returned time can be pruned. Prompts read everything after the
+/// summary plus the last `min_messages`, so those always stay, as do the ones the policy keeps.
+/// Returns `None` for channels without a summary.
+async fn prune_before(
+    database: &Database,
+    channel: u64,
+    policy: RetentionPolicy,
+    min_messages: i64,
+) -> Result<Option<NaiveDateTime>, sqlx::error::Error> {
+    let Some(summary) = database.get_summary(channel).await? else {
+        return Ok(None);
+    };
+    let mut before = summary.last_update;
+
+    if let Some(days) = policy.keep_days {
+        before = before.min(Utc::now().naive_utc() - Duration::days(days as i64));
+    }
+
+    let keep = (policy.keep_messages.unwrap_or(0) as i64).max(min_messages);
+    if keep > 0 {
+        let kept = database.get_messages_by_count(channel, keep).await?;
+        if let Some(oldest) = kept.first() {
+            before = before.min(oldest.date_time);
+        }
+    }
+    Ok(Some(before))
+}
+
+pub struct Retention {
+    database: Database,
+}
+
+impl Retention {
+    pub fn new(database: Database) -> Self {
+        Self { database }
+    }
+
+    pub async fn start(&self) {
+        loop {
+            tokio::time::sleep(config::get().retention.interval()).await;
+            prune_now(&self.database, &config::get()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChannelRetention;
+    use crate::database::{DbMessage, MemoryDatabase, RevisionSource};
+
+    fn config(action: RetentionAction, keep_messages: u64) -> Config {
+        let mut config = Config::default();
+        config.chat.min_messages = 2;
+        config.retention.action = action;
+        config.retention.keep_messages = Some(keep_messages);
+        config
+    }
+
+    async fn channel(database: &Database, channel: u64, count: i64) {
+        let start = Utc::now().naive_utc() - Duration::days(10);
+        for i in 0..count {
+            database
+                .add_message(&DbMessage {
+                    channel: channel.to_string(),
+                    scope: "1".to_string(),
+                    sender_id: "42".to_string(),
+                    sender: "Bob".to_string(),
+                    message: format!("m{}", i),
+                    date_time: start + Duration::minutes(i),
+                    message_id: None,
+                    raw_message: None,
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn archives_only_summarized_messages_beyond_the_kept_ones() {
+        let database = Database::with_storage(MemoryDatabase::new());
+        channel(&database, 1, 10).await;
+        channel(&database, 2, 10).await;
+        database
+            .update_summary(1, "summary", RevisionSource::Admin, None, None)
+            .await
+            .unwrap();
+
+        let report = prune_now(&database, &config(RetentionAction::Archive, 3)).await;
+        assert_eq!(report.channels, 1);
+        assert_eq!(report.archived, 7);
+        assert_eq!(report.unsummarized, 1);
+
+        let remaining = database.get_messages_by_count(1, 100).await.unwrap();
+        let texts = remaining
+            .iter()
+            .map(|m| m.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["m7", "m8", "m9"]);
+        let archived = database.get_archived_messages(1).await.unwrap();
+        assert_eq!(archived.len(), 7);
+        assert_eq!(archived[0].message, "m0");
+        assert_eq!(
+            database.get_messages_by_count(2, 100).await.unwrap().len(),
+            10
+        );
+
+        // nothing left to prune
+        let report = prune_now(&database, &config(RetentionAction::Archive, 3)).await;
+        assert_eq!(report.archived, 0);
+    }
+
+    #[tokio::test]
+    async fn channel_overrides_and_prompt_minimum() {
+        let database = Database::with_storage(MemoryDatabase::new());
+        channel(&database, 1, 10).await;
+        channel(&database, 2, 10).await;
+        database
+            .update_summary(1, "summary", RevisionSource::Admin, None, None)
+            .await
+            .unwrap();
+        database
+            .update_summary(2, "summary", RevisionSource::Admin, None, None)
+            .await
+            .unwrap();
+
+        let mut config = config(RetentionAction::Keep, 0);
+        config.retention.channels.push(ChannelRetention {
+            channel: 2,
+            action: Some(RetentionAction::Delete),
+            keep_days: None,
+            keep_messages: None,
+        });
+        let report = prune_now(&database, &config).await;
+        assert_eq!(report.deleted, 8);
+        assert_eq!(
+            database.get_messages_by_count(1, 100).await.unwrap().len(),
+            10
+        );
+        // the prompt still gets its minimum
+        assert_eq!(
+            database.get_messages_by_count(2, 100).await.unwrap().len(),
+            2
+        );
+        assert!(database.get_archived_messages(2).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_recent_days() {
+        let database = Database::with_storage(MemoryDatabase::new());
+        channel(&database, 1, 10).await;
+        database
+            .update_summary(1, "summary", RevisionSource::Admin, None, None)
+            .await
+            .unwrap();
+
+        let mut config = config(RetentionAction::Delete, 0);
+        config.retention.keep_days = Some(30);
+        let report = prune_now(&database, &config).await;
+        assert_eq!(report.deleted, 0);
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Utc};
+use regex::Regex;
+use tracing::info;
+
+use crate::config::{self, Clock};
+use crate::database::{DbPeriodSummary, SummaryLevel};
+use crate::gpt::ChatGPT;
+use crate::prompts::{channel_clock, get_rollup_prompt};
+use crate::Database;
+
+/// A summary to make out of others.
+#[derive(Debug)]
+pub struct RollUp<'a> {
+    pub level: SummaryLevel,
+    /// Oldest first.
+    pub parts: Vec<&'a DbPeriodSummary>,
+}
+
+fn week_start(date: NaiveDate) -> NaiveDate {
+    date - Duration::days(date.weekday().num_days_from_monday().into())
+}
+
+/// Groups summaries by the day or week they end in.
+fn by_period(
+    parts: Vec<&DbPeriodSummary>,
+    period: impl Fn(NaiveDate) -> NaiveDate,
+) -> BTreeMap<NaiveDate, Vec<&DbPeriodSummary>> {
+    let mut groups = BTreeMap::<_, Vec<_>>::new();
+    for part in parts {
+        groups
+            .entry(period(part.period_end.date()))
+            .or_default()
+            .push(part);
+    }
+    groups
+}
+
+/// What to roll up of the summaries of one level: sessions of finished days into daily
+/// summaries, days of finished weeks into weekly ones, and the weeks beyond the last
+/// `weekly_summaries` into the long-term summary. Days and weeks are in UTC.
+pub fn plan(
+    summaries: &[DbPeriodSummary],
+    level: SummaryLevel,
+    now: NaiveDateTime,
+    weekly_summaries: usize,
+) -> Vec<RollUp<'_>> {
+    let of_level = |level: SummaryLevel| {
+        let level = level.to_string();
+        summaries
+            .iter()
+            .filter(move |s| s.level == level)
+            .collect::<Vec<_>>()
+    };
+    match level {
+        SummaryLevel::Session => by_period(of_level(level), |date| date)
+            .into_iter()
+            .filter(|(day, _)| *day < now.date())
+            .map(|(_, parts)| RollUp {
+                level: SummaryLevel::Daily,
+                parts,
+            })
+            .collect(),
+        SummaryLevel::Daily => by_period(of_level(level), week_start)
+            .into_iter()
+            .filter(|(week, _)| *week < week_start(now.date()))
+            .map(|(_, parts)| RollUp {
+                level: SummaryLevel::Weekly,
+                parts,
+            })
+            .collect(),
+        SummaryLevel::Weekly => {
+            let weeks = of_level(level);
+            if weeks.len() <= weekly_summaries {
+                return Vec::new();
+            }
+            let mut parts = of_level(SummaryLevel::LongTerm);
+            parts.extend(&weeks[..weeks.len() - weekly_summaries]);
+            parts.sort_by_key(|s| s.period_start);
+            vec![RollUp {
+                level: SummaryLevel::LongTerm,
+                parts,
+            }]
+        }
+        SummaryLevel::LongTerm => Vec::new(),
+    }
+}
+
+/// Rolls up what is due in a channel, level by level.
+/// A single summary just moves up a level, more are combined by the model.
+pub async fn roll_up(
+    gpt: &ChatGPT,
+    database: &Database,
+    channel_id: u64,
+    weekly_summaries: usize,
+) -> anyhow::Result<()> {
+    for level in [
+        SummaryLevel::Session,
+        SummaryLevel::Daily,
+        SummaryLevel::Weekly,
+    ] {
+        let summaries = database.get_period_summaries(channel_id).await?;
+        let now = Utc::now().naive_utc();
+        for roll_up in plan(&summaries, level, now, weekly_summaries) {
+            let summary = match roll_up.parts[..] {
+                [part] => part.summary.clone(),
+                _ => {
+                    let clock = channel_clock(database, channel_id, &config::get().time).await?;
+                    combine(gpt, &roll_up.parts, &clock).await?
+                }
+            };
+            let parts = roll_up.parts.iter().map(|s| s.id).collect::<Vec<_>>();
+            let summary = DbPeriodSummary {
+                id: 0,
+                channel: channel_id.to_string(),
+                level: roll_up.level.to_string(),
+                period_start: roll_up.parts.iter().map(|s| s.period_start).min().unwrap(),
+                period_end: roll_up.parts.iter().map(|s| s.period_end).max().unwrap(),
+                summary,
+                created_at: now,
+            };
+            database.roll_up_summaries(&parts, &summary).await?;
+            info!(
+                "Rolled up {} summaries into a {} summary for channel {}",
+                parts.len(),
+                roll_up.level,
+                channel_id
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn combine(
+    gpt: &ChatGPT,
+    parts: &[&DbPeriodSummary],
+    clock: &Clock,
+) -> anyhow::Result<String> {
+    let prompt = get_rollup_prompt(parts, clock)?;
+    let response = gpt
+        .send(&prompt, config::get().summarizer.temperature)
+        .await?;
+    let re_summary = Regex::new(r"(?s)\bSUMMARY (.+?) END\b").unwrap();
+    re_summary
+        .captures(&response.message.content)
+        .and_then(|cap| cap.get(1))
+        .map(|summary| summary.as_str().trim().to_string())
+        .ok_or_else(|| anyhow::anyhow!("no summary in roll-up response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(id: i64, level: SummaryLevel, start: &str, end: &str) -> DbPeriodSummary {
+        let parse = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        DbPeriodSummary {
+            id,
+            channel: "1".to_string(),
+            level: level.to_string(),
+            period_start: parse(start),
+            period_end: parse(end),
+            summary: format!("summary {}", id),
+            created_at: parse(end),
+        }
+    }
+
+    fn ids(roll_ups: &[RollUp]) -> Vec<(SummaryLevel, Vec<i64>)> {
+        roll_ups
+            .iter()
+            .map(|r| (r.level, r.parts.iter().map(|s| s.id).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn sessions_of_finished_days_become_daily() {
+        let summaries = [
+            summary(
+                1,
+                SummaryLevel::Session,
+                "2023-04-24 09:00",
+                "2023-04-24 10:00",
+            ),
+            summary(
+                2,
+                SummaryLevel::Session,
+                "2023-04-24 22:00",
+                "2023-04-25 01:00",
+            ),
+            summary(
+                3,
+                SummaryLevel::Session,
+                "2023-04-25 09:00",
+                "2023-04-25 10:00",
+            ),
+            summary(
+                4,
+                SummaryLevel::Session,
+                "2023-04-26 09:00",
+                "2023-04-26 10:00",
+            ),
+            summary(
+                5,
+                SummaryLevel::Daily,
+                "2023-04-23 09:00",
+                "2023-04-23 10:00",
+            ),
+        ];
+        let now = summaries[4].period_end + Duration::days(3);
+        assert_eq!(
+            ids(&plan(&summaries, SummaryLevel::Session, now, 4)),
+            [
+                (SummaryLevel::Daily, vec![1]),
+                (SummaryLevel::Daily, vec![2, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn days_of_finished_weeks_become_weekly() {
+        // 2023-04-24 is a Monday
+        let summaries = [
+            summary(
+                1,
+                SummaryLevel::Daily,
+                "2023-04-22 09:00",
+                "2023-04-23 10:00",
+            ),
+            summary(
+                2,
+                SummaryLevel::Daily,
+                "2023-04-24 09:00",
+                "2023-04-24 10:00",
+            ),
+            summary(
+                3,
+                SummaryLevel::Daily,
+                "2023-04-30 09:00",
+                "2023-04-30 10:00",
+            ),
+            summary(
+                4,
+                SummaryLevel::Daily,
+                "2023-05-01 09:00",
+                "2023-05-01 10:00",
+            ),
+        ];
+        let now = summaries[3].period_end + Duration::days(1);
+        assert_eq!(
+            ids(&plan(&summaries, SummaryLevel::Daily, now, 4)),
+            [
+                (SummaryLevel::Weekly, vec![1]),
+                (SummaryLevel::Weekly, vec![2, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn old_weeks_fold_into_the_long_term_summary() {
+        let summaries = [
+            summary(
+                1,
+                SummaryLevel::LongTerm,
+                "2023-01-01 09:00",
+                "2023-03-01 10:00",
+            ),
+            summary(
+                2,
+                SummaryLevel::Weekly,
+                "2023-03-06 09:00",
+                "2023-03-12 10:00",
+            ),
+            summary(
+                3,
+                SummaryLevel::Weekly,
+                "2023-03-13 09:00",
+                "2023-03-19 10:00",
+            ),
+            summary(
+                4,
+                SummaryLevel::Weekly,
+                "2023-03-20 09:00",
+                "2023-03-26 10:00",
+            ),
+        ];
+        let now = summaries[3].period_end + Duration::days(1);
+        assert!(plan(&summaries, SummaryLevel::Weekly, now, 3).is_empty());
+        assert_eq!(
+            ids(&plan(&summaries, SummaryLevel::Weekly, now, 2)),
+            [(SummaryLevel::LongTerm, vec![1, 2])]
+        );
+        assert!(plan(&summaries, SummaryLevel::LongTerm, now, 0).is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+use serde::Deserialize;
+
+/// Longest message Discord takes, in characters.
+pub const MESSAGE_LIMIT: usize = 2000;
+
+/// The file type of replies sent as attachments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyFile {
+    #[default]
+    Md,
+    Txt,
+}
+
+impl ReplyFile {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ReplyFile::Md => "reply.md",
+            ReplyFile::Txt => "reply.txt",
+        }
+    }
+}
+
+/// One line of the text, or part of a line too long for a message.
+struct Piece<'a> {
+    text: &'a str,
+    /// The fence of the code block the piece is in.
+    fence: Option<&'a str>,
+    /// The piece starts the fence line that opens its code block.
+    opening: bool,
+    /// The piece ends the fence line that closes its code block.
+    closing: bool,
+    /// A blank line follows, so a message may end here.
+    paragraph_end: bool,
+    /// The piece goes on the line of the piece before.
+    continues: bool,
+}
+
+fn chars(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// The byte index after the first `count` characters.
+fn char_index(text: &str, count: usize) -> usize {
+    text.char_indices()
+        .nth(count)
+        .map_or(text.len(), |(i, _)| i)
+}
+
+/// Splits a line into parts of at most `max` characters: after a sentence if there is one,
+/// else between words, else anywhere.
+fn split_line(line: &str, max: usize, prose: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = line;
+    while chars(rest) > max {
+        let head = &rest[..char_index(rest, max)];
+        let sentence = prose
+            .then(|| {
+                [". ", "! ", "? ", "… "]
+                    .iter()
+                    .filter_map(|end| head.rfind(end).map(|i| i + end.len()))
+                    .max()
+            })
+            .flatten();
+        let word = prose.then(|| head.rfind(' ').map(|i| i + 1)).flatten();
+        let cut = sentence
+            .or(word)
+            .filter(|cut| *cut > 0)
+            .unwrap_or(head.len());
+        parts.push(&rest[..cut]);
+        rest = &rest[cut..];
+    }
+    parts.push(rest);
+    parts
+}
+
+fn pieces(text: &str, limit: usize) -> Vec<Piece<'_>> {
+    let lines = text.lines().collect::<Vec<_>>();
+    let mut pieces = Vec::new();
+    let mut fence = None;
+    for (i, line) in lines.iter().enumerate() {
+        let is_fence = line.trim_start().starts_with("```");
+        // a fence line belongs inside its block, on both ends
+        let inside = if is_fence && fence.is_none() {
+            Some(line.trim())
+        } else {
+            fence
+        };
+        let reserve = inside.map_or(0, |f| chars(f) + 5);
+        let max = limit.saturating_sub(reserve).max(1);
+        let parts = split_line(line, max, inside.is_none());
+        let last = parts.len() - 1;
+        for (n, part) in parts.into_iter().enumerate() {
+            pieces.push(Piece {
+                text: part,
+                fence: inside,
+                opening: is_fence && fence.is_none() && n == 0,
+                closing: is_fence && fence.is_some() && n == last,
+                paragraph_end: false,
+                continues: n > 0,
+            });
+        }
+        fence = match (is_fence, fence) {
+            (true, None) => Some(line.trim()),
+            (true, Some(_)) => None,
+            (false, fence) => fence,
+        };
+        if fence.is_none() && lines.get(i + 1).is_some_and(|l| l.trim().is_empty()) {
+            if let Some(piece) = pieces.last_mut() {
+                piece.paragraph_end = true;
+            }
+        }
+    }
+    pieces
+}
+
+/// Renders a message: a fence reopens the code block it starts in, and one closes
+/// the code block it ends in.
+fn render(pieces: &[Piece]) -> String {
+    let mut message = String::new();
+    let first = &pieces[0];
+    if let Some(fence) = first.fence {
+        if !first.opening {
+            message.push_str(fence);
+            message.push('\n');
+        }
+    }
+    for (i, piece) in pieces.iter().enumerate() {
+        if i > 0 && !piece.continues {
+            message.push('\n');
+        }
+        message.push_str(piece.text);
+    }
+    let last = &pieces[pieces.len() - 1];
+    if last.fence.is_some() && !last.closing {
+        message.push_str("\n```");
+    }
+    message.trim_matches(['\n', ' ']).to_string()
+}
+
+/// Splits text into messages of at most `limit` characters. Messages end after a
+/// paragraph when one is near, else after a line, sentence or word. Code blocks split
+/// across messages are closed at the end of one and reopened at the start of the next.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let pieces = pieces(text.trim(), limit);
+    let mut messages = Vec::new();
+    let mut start = 0;
+    while start < pieces.len() {
+        let mut end = start + 1;
+        while end < pieces.len() && chars(&render(&pieces[start..=end])) <= limit {
+            end += 1;
+        }
+        if end < pieces.len() {
+            // rather end after a paragraph, unless that leaves a short message
+            if let Some(paragraph) = (start..end).rev().find(|&i| pieces[i].paragraph_end) {
+                if chars(&render(&pieces[start..=paragraph])) >= limit / 2 {
+                    end = paragraph + 1;
+                }
+            }
+        }
+        let message = render(&pieces[start..end]);
+        if !message.trim().is_empty() {
+            messages.push(message);
+        }
+        start = end;
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_one_message() {
+        assert_eq!(split_message("  hi\n\nthere \n", 2000), ["hi\n\nthere"]);
+        assert!(split_message("", 2000).is_empty());
+    }
+
+    #[test]
+    fn splits_after_paragraphs_and_sentences() {
+        let text = "First paragraph here.\n\nSecond one. It is longer than that.";
+        assert_eq!(
+            split_message(text, 40),
+            [
+                "First paragraph here.",
+                "Second one. It is longer than that."
+            ]
+        );
+        let text = "One sentence. Another sentence! And a third one?";
+        assert_eq!(
+            split_message(text, 30),
+            ["One sentence.", "Another sentence!", "And a third one?"]
+        );
+        let messages = split_message(&"word ".repeat(1000), 2000);
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|m| chars(m) <= 2000));
+        assert_eq!(split_message(&"x".repeat(25), 10).len(), 3);
+    }
+
+    #[test]
+    fn code_blocks_are_reopened() {
+        let code = (1..=6)
+            .map(|i| format!("let x{} = {};", i, i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = format!("Here:\n```rust\n{}\n```\nDone.", code);
+        let messages = split_message(&text, 60);
+        assert!(messages.iter().all(|m| chars(m) <= 60), "{:?}", messages);
+        for message in &messages {
+            assert_eq!(message.matches("```").count() % 2, 0, "{:?}", messages);
+        }
+        assert!(messages[0].starts_with("Here:\n```rust\nlet x1"));
+        assert!(messages[1].starts_with("```rust\n"));
+        assert!(messages.last().unwrap().ends_with("```\nDone."));
+        let joined = messages.join("\n").replace("```\n```rust\n", "");
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn bare_code_blocks_are_closed_once() {
+        let text = "Here:\n```\nlet x = 1;\n```";
+        assert_eq!(split_message(text, 2000), [text]);
+        let code = (1..=6)
+            .map(|i| format!("let x{} = {};", i, i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = format!("Here:\n```\n{}\n```\nDone.", code);
+        let messages = split_message(&text, 40);
+        for message in &messages {
+            assert_eq!(message.matches("```").count() % 2, 0, "{:?}", messages);
+        }
+        assert!(messages[1].starts_with("```\n"));
+        assert!(messages.last().unwrap().ends_with("```\nDone."));
+        let joined = messages.join("\n").replace("```\n```\n", "");
+        assert_eq!(joined, text);
+    }
+}
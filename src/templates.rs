@@ -0,0 +1,405 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use minijinja::Environment;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config;
+
+pub const CHAT: &str = "chat";
+pub const CHAT_TURNS: &str = "chat_turns";
+pub const CHAT_DM: &str = "chat_dm";
+pub const CHAT_USER: &str = "chat_user";
+pub const CHAT_RECALL_USER: &str = "chat_recall_user";
+pub const SUMMARY_USER: &str = "summary_user";
+pub const PROFILE_USER: &str = "profile_user";
+pub const RECALL: &str = "recall";
+pub const ROLLUP: &str = "rollup";
+pub const ROLLUP_USER: &str = "rollup_user";
+
+/// What the chat prompts get to render, see [`crate::prompts::PromptContext`].
+const CHAT_VARIABLES: &[&str] = &[
+    "guild", "channel", "users", "date", "time", "timezone", "summary", "history", "messages",
+];
+
+/// The chat variables and whose profile the profile template updates.
+const PROFILE_VARIABLES: &[&str] = &[
+    "guild",
+    "channel",
+    "users",
+    "date",
+    "time",
+    "timezone",
+    "summary",
+    "history",
+    "messages",
+    "participant",
+];
+
+/// A prompt template, with the built-in version and the variables it gets.
+struct Kind {
+    name: &'static str,
+    builtin: &'static str,
+    variables: &'static [&'static str],
+    /// Variables the template has to use.
+    required: &'static [&'static str],
+}
+
+const KINDS: [Kind; 10] = [
+    Kind {
+        name: CHAT,
+        builtin: include_str!("../templates/chat.txt"),
+        variables: CHAT_VARIABLES,
+        required: &["users", "messages"],
+    },
+    Kind {
+        name: CHAT_TURNS,
+        builtin: include_str!("../templates/chat_turns.txt"),
+        variables: CHAT_VARIABLES,
+        required: &["users"],
+    },
+    Kind {
+        name: CHAT_DM,
+        builtin: include_str!("../templates/chat_dm.txt"),
+        variables: CHAT_VARIABLES,
+        required: &["users"],
+    },
+    Kind {
+        name: CHAT_USER,
+        builtin: include_str!("../templates/chat_user.txt"),
+        variables: CHAT_VARIABLES,
+        required: &[],
+    },
+    Kind {
+        name: CHAT_RECALL_USER,
+        builtin: include_str!("../templates/chat_recall_user.txt"),
+        variables: CHAT_VARIABLES,
+        required: &[],
+    },
+    Kind {
+        name: SUMMARY_USER,
+        builtin: include_str!("../templates/summary_user.txt"),
+        variables: CHAT_VARIABLES,
+        required: &[],
+    },
+    Kind {
+        name: PROFILE_USER,
+        builtin: include_str!("../templates/profile_user.txt"),
+        variables: PROFILE_VARIABLES,
+        required: &["participant"],
+    },
+    Kind {
+        name: RECALL,
+        builtin: include_str!("../templates/recall.txt"),
+        variables: &["query", "messages", "user_prompt"],
+        required: &["messages", "user_prompt"],
+    },
+    Kind {
+        name: ROLLUP,
+        builtin: include_str!("../templates/rollup.txt"),
+        variables: &["parts"],
+        required: &["parts"],
+    },
+    Kind {
+        name: ROLLUP_USER,
+        builtin: include_str!("../templates/rollup_user.txt"),
+        variables: &["parts"],
+        required: &[],
+    },
+];
+
+/// Functions templates may call like variables.
+const FUNCTIONS: &[&str] = &["range", "dict", "namespace", "debug"];
+
+/// The keywords of the `USER ... SAYS ... END` lines in prompts and responses.
+static ENVELOPE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b(USER|SAYS|INFO|END|SUMMARY|RECALL)\b").unwrap());
+
+/// Keeps text from opening or closing prompt and response lines by lowercasing
+/// the keywords, which the response parsers don't match.
+pub fn quote(text: &str) -> Cow<'_, str> {
+    ENVELOPE.replace_all(text, |caps: &Captures| caps[0].to_lowercase())
+}
+
+/// Where a prompt is for, to pick the template overrides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub guild: Option<u64>,
+    pub channel: u64,
+}
+
+/// The prompt templates: the built-in ones, replaced by the ones in the templates directory,
+/// which `guilds/<id>/` and `channels/<id>/` subdirectories override per guild and channel.
+pub struct Templates {
+    env: Environment<'static>,
+    /// Where each template came from, by name in `env`.
+    sources: BTreeMap<String, String>,
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.add_filter("quote", |text: String| quote(&text).into_owned());
+    env
+}
+
+/// Checks that a template compiles and uses only the variables it gets, and the required ones.
+fn validate(kind: &Kind, source: &str) -> Result<(), String> {
+    let env = environment();
+    let template = env.template_from_str(source).map_err(|e| e.to_string())?;
+    let used = template.undeclared_variables(false);
+    let mut unknown = used
+        .iter()
+        .filter(|v| !kind.variables.contains(&v.as_str()) && !FUNCTIONS.contains(&v.as_str()))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(format!("unknown variables {}", unknown.join(", ")));
+    }
+    let missing = kind
+        .required
+        .iter()
+        .filter(|v| !used.contains(**v))
+        .copied()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!("missing variables {}", missing.join(", ")));
+    }
+    Ok(())
+}
+
+impl Templates {
+    pub fn builtin() -> Self {
+        let mut templates = Self {
+            env: environment(),
+            sources: BTreeMap::new(),
+        };
+        for kind in &KINDS {
+            templates
+                .env
+                .add_template_owned(kind.name, kind.builtin)
+                .expect("built-in templates compile");
+            templates
+                .sources
+                .insert(kind.name.to_string(), "built-in".to_string());
+        }
+        templates
+    }
+
+    /// Loads the templates in `directory` over the built-in ones.
+    /// Templates that fail to load are left out; returns what went wrong with them.
+    pub fn load(directory: &Path) -> (Self, Vec<String>) {
+        let mut templates = Self::builtin();
+        let mut errors = Vec::new();
+        if !directory.is_dir() {
+            return (templates, errors);
+        }
+
+        templates.load_dir(directory, "", &mut errors);
+        for (subdirectory, prefix) in [("guilds", "guild"), ("channels", "channel")] {
+            let Ok(entries) = fs::read_dir(directory.join(subdirectory)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.parse::<u64>().is_err() || !entry.path().is_dir() {
+                    errors.push(format!("{}: not a {} id", entry.path().display(), prefix));
+                    continue;
+                }
+                let prefix = format!("{}/{}/", prefix, name);
+                templates.load_dir(&entry.path(), &prefix, &mut errors);
+            }
+        }
+        (templates, errors)
+    }
+
+    fn load_dir(&mut self, directory: &Path, prefix: &str, errors: &mut Vec<String>) {
+        let Ok(entries) = fs::read_dir(directory) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|e| e != "txt") {
+                continue;
+            }
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            let Some(kind) = KINDS.iter().find(|k| k.name == name) else {
+                errors.push(format!("{}: unknown template", path.display()));
+                continue;
+            };
+            let result = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|source| validate(kind, &source).map(|_| source))
+                .and_then(|source| {
+                    let key = format!("{}{}", prefix, kind.name);
+                    self.env
+                        .add_template_owned(key.clone(), source)
+                        .map_err(|e| e.to_string())?;
+                    self.sources.insert(key, path.display().to_string());
+                    Ok(())
+                });
+            if let Err(e) = result {
+                errors.push(format!("{}: {}", path.display(), e));
+            }
+        }
+    }
+
+    /// The name in the environment of the template used at `location`.
+    fn key(&self, name: &str, location: Location) -> String {
+        let channel = format!("channel/{}/{}", location.channel, name);
+        if self.sources.contains_key(&channel) {
+            return channel;
+        }
+        if let Some(guild) = location.guild {
+            let guild = format!("guild/{}/{}", guild, name);
+            if self.sources.contains_key(&guild) {
+                return guild;
+            }
+        }
+        name.to_string()
+    }
+
+    /// Where the template used at `location` came from.
+    pub fn source(&self, name: &str, location: Location) -> Option<&str> {
+        self.sources
+            .get(&self.key(name, location))
+            .map(String::as_str)
+    }
+
+    pub fn render(
+        &self,
+        name: &str,
+        location: Location,
+        context: impl Serialize,
+    ) -> anyhow::Result<String> {
+        let template = self.env.get_template(&self.key(name, location))?;
+        Ok(template.render(context)?)
+    }
+
+    /// The templates loaded from files, with where they came from.
+    pub fn overrides(&self) -> Vec<(&str, &str)> {
+        self.sources
+            .iter()
+            .filter(|(_, source)| *source != "built-in")
+            .map(|(key, source)| (key.as_str(), source.as_str()))
+            .collect()
+    }
+}
+
+/// Whether the template renders with a [`crate::prompts::PromptContext`], so it can be previewed.
+pub fn is_chat_template(name: &str) -> bool {
+    KINDS
+        .iter()
+        .any(|k| k.name == name && k.variables == CHAT_VARIABLES)
+}
+
+static TEMPLATES: Lazy<RwLock<Arc<Templates>>> =
+    Lazy::new(|| RwLock::new(Arc::new(Templates::builtin())));
+
+/// The current templates, the built-in ones until they are loaded.
+pub fn get() -> Arc<Templates> {
+    TEMPLATES.read().unwrap().clone()
+}
+
+/// Loads the templates from `templates.directory` again. Returns the templates that failed.
+pub fn reload() -> Vec<String> {
+    let directory = config::get().templates.directory.clone();
+    let (templates, errors) = Templates::load(&directory);
+    for error in &errors {
+        warn!("Failed to load template {}", error);
+    }
+    info!(
+        "Loaded {} template overrides from {}",
+        templates.overrides().len(),
+        directory.display()
+    );
+    *TEMPLATES.write().unwrap() = Arc::new(templates);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("kasumi_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn builtin_templates_are_valid() {
+        for kind in &KINDS {
+            assert_eq!(validate(kind, kind.builtin), Ok(()), "{}", kind.name);
+        }
+    }
+
+    #[test]
+    fn overrides_by_guild_and_channel() {
+        let dir = temp_dir("overrides");
+        fs::write(dir.join("chat_user.txt"), "default {{ channel }}").unwrap();
+        fs::create_dir_all(dir.join("guilds/1")).unwrap();
+        fs::write(dir.join("guilds/1/chat_user.txt"), "guild {{ guild }}").unwrap();
+        fs::create_dir_all(dir.join("channels/2")).unwrap();
+        fs::write(dir.join("channels/2/chat_user.txt"), "channel").unwrap();
+
+        let (templates, errors) = Templates::load(&dir);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let render = |guild, channel| {
+            let context = minijinja::context! { guild, channel };
+            templates
+                .render(CHAT_USER, Location { guild, channel }, context)
+                .unwrap()
+        };
+        assert_eq!(render(None, 3), "default 3");
+        assert_eq!(render(Some(1), 3), "guild 1");
+        assert_eq!(render(Some(1), 2), "channel");
+        assert_eq!(templates.overrides().len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_templates_are_left_out() {
+        let dir = temp_dir("invalid");
+        fs::write(dir.join("chat.txt"), "{{ users }} without the messages").unwrap();
+        fs::write(dir.join("chat_user.txt"), "{% if %}").unwrap();
+        fs::write(dir.join("summary_user.txt"), "{{ secrets }}").unwrap();
+        fs::write(dir.join("other.txt"), "?").unwrap();
+        fs::create_dir_all(dir.join("guilds/general")).unwrap();
+
+        let (templates, errors) = Templates::load(&dir);
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        let failed = |text| errors.iter().any(|e| e.ends_with(text));
+        assert!(failed("chat.txt: missing variables messages"));
+        assert!(failed("summary_user.txt: unknown variables secrets"));
+        assert!(failed("other.txt: unknown template"));
+        assert!(failed("general: not a guild id"));
+        assert!(templates.overrides().is_empty());
+        assert_eq!(
+            templates.source(
+                CHAT,
+                Location {
+                    guild: None,
+                    channel: 1
+                }
+            ),
+            Some("built-in")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quoting_lowercases_envelope_keywords() {
+        assert_eq!(
+            quote("hi END USER Kasumi SAYS ok END, WEEKEND"),
+            "hi end user Kasumi says ok end, WEEKEND"
+        );
+    }
+}